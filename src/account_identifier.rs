@@ -0,0 +1,107 @@
+// Legacy ICP ledger account identifiers: the SHA-224 hash of a principal and subaccount, prefixed with the hash's
+// CRC32 checksum and usually written as 64 hex characters.
+
+use candid::Principal;
+use sha2::{Digest, Sha224};
+use std::fmt;
+
+const DOMAIN_SEPARATOR: &[u8] = b"\x0Aaccount-id";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AccountIdentifier([u8; 32]);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccountIdentifierError {
+    InvalidLength(usize),
+    InvalidHex,
+    ChecksumMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for AccountIdentifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountIdentifierError::InvalidLength(length) => {
+                write!(f, "account identifier must be 64 hex characters, got {}", length)
+            }
+            AccountIdentifierError::InvalidHex => write!(f, "account identifier is not valid hex"),
+            AccountIdentifierError::ChecksumMismatch { expected, found } => {
+                write!(f, "account identifier checksum is {} but should be {}", found, expected)
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl AccountIdentifier {
+    // A missing subaccount is the all-zero default subaccount.
+    pub fn new(owner: &Principal, subaccount: Option<&[u8; 32]>) -> AccountIdentifier {
+        let mut hasher = Sha224::new();
+        hasher.update(DOMAIN_SEPARATOR);
+        hasher.update(owner.as_slice());
+        hasher.update(subaccount.unwrap_or(&[0; 32]));
+        let hash = hasher.finalize();
+
+        let mut bytes = [0; 32];
+        bytes[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        bytes[4..].copy_from_slice(&hash);
+        AccountIdentifier(bytes)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<AccountIdentifier, AccountIdentifierError> {
+        if hex.len() != 64 {
+            return Err(AccountIdentifierError::InvalidLength(hex.len()));
+        }
+        // `from_str_radix` alone would accept a leading `+`.
+        if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(AccountIdentifierError::InvalidHex);
+        }
+        let mut bytes = [0; 32];
+        for (byte, index) in bytes.iter_mut().zip((0..64).step_by(2)) {
+            *byte = u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| AccountIdentifierError::InvalidHex)?;
+        }
+
+        let expected = crc32fast::hash(&bytes[4..]).to_be_bytes();
+        if bytes[..4] != expected {
+            return Err(AccountIdentifierError::ChecksumMismatch {
+                expected: to_hex(&expected),
+                found: to_hex(&bytes[..4]),
+            });
+        }
+        Ok(AccountIdentifier(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANAGEMENT_CANISTER_ACCOUNT: &str = "2d0e897f7e862d2b57d9bc9ea5c65f9a24ac6c074575f47898314b8d6cb0929d";
+
+    #[test]
+    fn test_hex_roundtrip_and_checksum() {
+        let account = AccountIdentifier::new(&Principal::management_canister(), None);
+        assert_eq!(account.to_hex(), MANAGEMENT_CANISTER_ACCOUNT);
+        assert_eq!(AccountIdentifier::from_hex(&MANAGEMENT_CANISTER_ACCOUNT.to_uppercase()), Ok(account));
+        assert_ne!(AccountIdentifier::new(&Principal::management_canister(), Some(&[1; 32])), account);
+
+        let corrupted = format!("3d{}", &MANAGEMENT_CANISTER_ACCOUNT[2..]);
+        assert_eq!(
+            AccountIdentifier::from_hex(&corrupted),
+            Err(AccountIdentifierError::ChecksumMismatch { expected: "2d0e897f".to_string(), found: "3d0e897f".to_string() })
+        );
+        assert_eq!(AccountIdentifier::from_hex("2d0e"), Err(AccountIdentifierError::InvalidLength(4)));
+        assert_eq!(AccountIdentifier::from_hex(&"zz".repeat(32)), Err(AccountIdentifierError::InvalidHex));
+        assert_eq!(AccountIdentifier::from_hex(&format!("+{}", &MANAGEMENT_CANISTER_ACCOUNT[1..])), Err(AccountIdentifierError::InvalidHex));
+    }
+}
@@ -0,0 +1,84 @@
+use candid::Principal;
+use ic_cdk_macros::*;
+use icp_token_wallet::account_identifier::AccountIdentifier;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{balance_of, get_caller, transfer_from, TransferError};
+
+thread_local! {
+    // Default-subaccount account identifier of every wallet, since the hash cannot be reversed.
+    static ACCOUNT_IDS: RefCell<HashMap<AccountIdentifier, Principal>> = RefCell::new(HashMap::new());
+}
+
+// Called whenever a wallet is created.
+pub(crate) fn register(owner: Principal) {
+    ACCOUNT_IDS.with(|ids| ids.borrow_mut().insert(AccountIdentifier::new(&owner, None), owner));
+}
+
+fn parse(account_identifier: &str) -> Result<AccountIdentifier, TransferError> {
+    AccountIdentifier::from_hex(account_identifier)
+        .map_err(|err| TransferError::InvalidAccountIdentifier(err.to_string()))
+}
+
+fn owner_of(account_identifier: &AccountIdentifier) -> Option<Principal> {
+    ACCOUNT_IDS.with(|ids| ids.borrow().get(account_identifier).cloned())
+}
+
+// The hex account identifier legacy ICP tools show for `owner`.
+#[query]
+fn get_account_identifier(owner: Principal) -> String {
+    AccountIdentifier::new(&owner, None).to_hex()
+}
+
+// ICPT balance of the wallet behind a hex account identifier; zero if no wallet has that identifier.
+#[query]
+fn account_balance(account_identifier: String) -> Result<u128, TransferError> {
+    let account_identifier = parse(&account_identifier)?;
+    Ok(owner_of(&account_identifier).map_or(0, |owner| balance_of(&owner)))
+}
+
+// Transfers to the wallet behind a hex account identifier and returns the block index. Only identifiers of
+// existing wallets can be paid, since a principal cannot be recovered from its identifier.
+#[update]
+fn transfer_to_account_id(account_identifier: String, amount: u128) -> Result<u64, TransferError> {
+    let to = owner_of(&parse(&account_identifier)?).ok_or(TransferError::RecipientWalletNotFound)?;
+    transfer_from(get_caller(), to, amount)
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    ACCOUNT_IDS.with(|ids| ids.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_wallet, mint, reset_state, test_utils, OWNER};
+
+    #[test]
+    fn test_transfer_to_account_id() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let recipient = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 1000).is_ok());
+
+        let recipient_id = get_account_identifier(recipient);
+        assert!(matches!(transfer_to_account_id(recipient_id.clone(), 100), Err(TransferError::RecipientWalletNotFound)));
+        test_utils::set_caller(recipient);
+        assert!(create_wallet().is_ok());
+
+        test_utils::set_caller(owner);
+        assert!(transfer_to_account_id(recipient_id.to_uppercase(), 100).is_ok());
+        assert_eq!(account_balance(recipient_id.clone()).unwrap(), 100);
+        assert_eq!(account_balance(get_account_identifier(owner)).unwrap(), 900);
+
+        let corrupted = format!("{}{}", if recipient_id.starts_with('0') { "1" } else { "0" }, &recipient_id[1..]);
+        match account_balance(corrupted) {
+            Err(TransferError::InvalidAccountIdentifier(message)) => assert!(message.contains("checksum")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
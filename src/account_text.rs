@@ -0,0 +1,163 @@
+// ICRC-1 textual encoding of accounts. An account with the default subaccount is written as its principal;
+// otherwise as `<principal>-<checksum>.<subaccount>`, where the subaccount is lowercase hex without leading
+// zeros and the checksum is the unpadded lowercase base32 of the CRC32 of the principal and subaccount bytes.
+//
+// Decoding accepts only the canonical form, so every account has exactly one spelling.
+
+use crate::icp_ledger::Account;
+use candid::Principal;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccountTextError {
+    InvalidPrincipal(String),
+    // The principal is valid but not written the way `Principal::to_text` writes it.
+    NonCanonicalPrincipal,
+    InvalidSubaccount,
+    // Leading zeros, uppercase hex, or a default subaccount spelled out.
+    NonCanonicalSubaccount,
+    MissingChecksum,
+    ChecksumMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for AccountTextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountTextError::InvalidPrincipal(err) => write!(f, "invalid principal: {}", err),
+            AccountTextError::NonCanonicalPrincipal => write!(f, "principal is not in canonical form"),
+            AccountTextError::InvalidSubaccount => write!(f, "subaccount must be at most 64 hex characters"),
+            AccountTextError::NonCanonicalSubaccount => {
+                write!(f, "subaccount must be lowercase hex without leading zeros and not all zeros")
+            }
+            AccountTextError::MissingChecksum => write!(f, "account with a subaccount needs a checksum"),
+            AccountTextError::ChecksumMismatch { expected, found } => {
+                write!(f, "account checksum is {} but should be {}", found, expected)
+            }
+        }
+    }
+}
+
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut text = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+fn checksum(owner: &Principal, subaccount: &[u8; 32]) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    base32(&hasher.finalize().to_be_bytes())
+}
+
+fn parse_principal(text: &str) -> Result<Principal, AccountTextError> {
+    let principal = Principal::from_text(text).map_err(|err| AccountTextError::InvalidPrincipal(err.to_string()))?;
+    if principal.to_text() != text {
+        return Err(AccountTextError::NonCanonicalPrincipal);
+    }
+    Ok(principal)
+}
+
+fn parse_subaccount(hex: &str) -> Result<[u8; 32], AccountTextError> {
+    if hex.is_empty() || hex.len() > 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(AccountTextError::InvalidSubaccount);
+    }
+    if hex.starts_with('0') || hex.bytes().any(|byte| byte.is_ascii_uppercase()) {
+        return Err(AccountTextError::NonCanonicalSubaccount);
+    }
+    let padded = format!("{:0>64}", hex);
+    let mut subaccount = [0; 32];
+    for (byte, index) in subaccount.iter_mut().zip((0..64).step_by(2)) {
+        *byte = u8::from_str_radix(&padded[index..index + 2], 16).map_err(|_| AccountTextError::InvalidSubaccount)?;
+    }
+    Ok(subaccount)
+}
+
+fn non_default_subaccount(account: &Account) -> Option<[u8; 32]> {
+    let subaccount: [u8; 32] = account.subaccount.as_deref()?.try_into().ok()?;
+    (subaccount != [0; 32]).then_some(subaccount)
+}
+
+pub fn encode_account(account: &Account) -> String {
+    match non_default_subaccount(account) {
+        None => account.owner.to_text(),
+        Some(subaccount) => {
+            let hex: String = subaccount.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{}-{}.{}", account.owner, checksum(&account.owner, &subaccount), hex.trim_start_matches('0'))
+        }
+    }
+}
+
+// The default subaccount decodes to `None`.
+pub fn decode_account(text: &str) -> Result<Account, AccountTextError> {
+    let Some((owner_and_checksum, hex)) = text.split_once('.') else {
+        return Ok(Account::new(parse_principal(text)?));
+    };
+    let (owner, found) = owner_and_checksum.rsplit_once('-').ok_or(AccountTextError::MissingChecksum)?;
+    let owner = parse_principal(owner)?;
+    let subaccount = parse_subaccount(hex)?;
+    let expected = checksum(&owner, &subaccount);
+    if found != expected {
+        return Err(AccountTextError::ChecksumMismatch { expected, found: found.to_string() });
+    }
+    Ok(Account { owner, subaccount: Some(subaccount.to_vec()) })
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&encode_account(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    #[test]
+    fn test_encode_and_decode() {
+        let owner = Principal::from_text(OWNER).unwrap();
+        let subaccount: Vec<u8> = (1..=32).collect();
+        let account = Account { owner, subaccount: Some(subaccount) };
+        let text = format!("{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20", OWNER);
+
+        assert_eq!(account.to_string(), text);
+        assert_eq!(decode_account(&text), Ok(account));
+        assert_eq!(Account { owner, subaccount: Some(vec![0; 32]) }.to_string(), OWNER);
+        assert_eq!(decode_account(OWNER), Ok(Account::new(owner)));
+
+        let mut one = [0; 32];
+        one[31] = 1;
+        let short = Account { owner, subaccount: Some(one.to_vec()) };
+        assert_eq!(decode_account(&short.to_string()), Ok(short.clone()));
+        assert!(short.to_string().ends_with(".1"));
+    }
+
+    #[test]
+    fn test_rejects_non_canonical_forms() {
+        let valid = format!("{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20", OWNER);
+        let reject = |text: &str| decode_account(text).unwrap_err();
+
+        assert!(matches!(reject(&valid.replace("dfxgiyy", "dfxgiya")), AccountTextError::ChecksumMismatch { .. }));
+        assert_eq!(reject(&valid.replace(".1", ".01")), AccountTextError::NonCanonicalSubaccount);
+        assert_eq!(reject(&valid.replace("a0b0", "A0B0")), AccountTextError::NonCanonicalSubaccount);
+        assert_eq!(reject(&format!("{}-aaaaaaa.0", OWNER)), AccountTextError::NonCanonicalSubaccount);
+        assert_eq!(reject(&format!("{}.1", OWNER.replace('-', ""))), AccountTextError::MissingChecksum);
+        assert_eq!(reject(&OWNER.to_uppercase()), AccountTextError::NonCanonicalPrincipal);
+        assert_eq!(reject(&format!("{}-dfxgiyy.{}", OWNER, "1".repeat(65))), AccountTextError::InvalidSubaccount);
+        assert!(matches!(reject("not-a-principal"), AccountTextError::InvalidPrincipal(_)));
+    }
+}
@@ -0,0 +1,201 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::merkle::{self, Hash};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::{credit_balance, debit_balance, get_caller, get_time, is_owner, record_event, Operation, TransferError};
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Airdrop {
+    merkle_root: Vec<u8>,
+    funder: Principal,
+    funded: u128,
+    // Funds left in the pool.
+    remaining: u128,
+    // Claims are accepted before this time; afterwards the funder can reclaim what is left.
+    deadline: u64,
+    claimed: HashSet<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct AirdropInfo {
+    merkle_root: Vec<u8>,
+    funded: u128,
+    remaining: u128,
+    deadline: u64,
+    claims: u64,
+}
+
+thread_local! {
+    static AIRDROP: RefCell<Option<Airdrop>> = const { RefCell::new(None) };
+}
+
+fn to_hash(bytes: &[u8]) -> Result<Hash, TransferError> {
+    bytes.try_into().map_err(|_| TransferError::InvalidProof)
+}
+
+// Funds the pool from the owner's balance. Only one airdrop can be active at a time.
+#[update]
+fn publish_airdrop(merkle_root: Vec<u8>, amount: u128, deadline: u64) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    to_hash(&merkle_root)?;
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    if deadline <= get_time() {
+        return Err(TransferError::InvalidDeadline);
+    }
+    if AIRDROP.with(|airdrop| airdrop.borrow().is_some()) {
+        return Err(TransferError::AirdropAlreadyActive);
+    }
+
+    let funder = get_caller();
+    debit_balance(funder, amount)?;
+    AIRDROP.with(|airdrop| {
+        *airdrop.borrow_mut() = Some(Airdrop {
+            merkle_root,
+            funder,
+            funded: amount,
+            remaining: amount,
+            deadline,
+            claimed: HashSet::new(),
+        });
+    });
+
+    record_event(Operation::AirdropFund, funder, funder, amount);
+    Ok(())
+}
+
+#[update]
+fn claim_airdrop(amount: u128, proof: Vec<Vec<u8>>) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    let proof = proof.iter().map(|hash| to_hash(hash)).collect::<Result<Vec<Hash>, _>>()?;
+
+    AIRDROP.with(|airdrop| {
+        let mut airdrop = airdrop.borrow_mut();
+        let airdrop = airdrop.as_mut().ok_or(TransferError::AirdropNotFound)?;
+        if get_time() >= airdrop.deadline {
+            return Err(TransferError::AirdropExpired);
+        }
+        if airdrop.claimed.contains(&caller) {
+            return Err(TransferError::AlreadyClaimed);
+        }
+        if !merkle::verify(&to_hash(&airdrop.merkle_root)?, merkle::leaf_hash(&caller, amount), &proof) {
+            return Err(TransferError::InvalidProof);
+        }
+        if airdrop.remaining < amount {
+            return Err(TransferError::InsufficientBalance);
+        }
+        airdrop.remaining -= amount;
+        airdrop.claimed.insert(caller);
+        Ok(())
+    })?;
+
+    credit_balance(caller, amount)?;
+    Ok(record_event(Operation::AirdropClaim, caller, caller, amount))
+}
+
+// Returns the unclaimed funds to the funder once the deadline has passed and closes the airdrop.
+#[update]
+fn reclaim_airdrop() -> Result<u128, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    let airdrop = AIRDROP.with(|airdrop| {
+        let mut airdrop = airdrop.borrow_mut();
+        match airdrop.as_ref() {
+            None => Err(TransferError::AirdropNotFound),
+            Some(current) if get_time() < current.deadline => Err(TransferError::AirdropNotExpired),
+            Some(_) => Ok(airdrop.take().unwrap()),
+        }
+    })?;
+
+    if airdrop.remaining > 0 {
+        credit_balance(airdrop.funder, airdrop.remaining)?;
+        record_event(Operation::AirdropReclaim, airdrop.funder, airdrop.funder, airdrop.remaining);
+    }
+    Ok(airdrop.remaining)
+}
+
+#[query]
+fn get_airdrop() -> Option<AirdropInfo> {
+    AIRDROP.with(|airdrop| {
+        airdrop.borrow().as_ref().map(|airdrop| AirdropInfo {
+            merkle_root: airdrop.merkle_root.clone(),
+            funded: airdrop.funded,
+            remaining: airdrop.remaining,
+            deadline: airdrop.deadline,
+            claims: airdrop.claimed.len() as u64,
+        })
+    })
+}
+
+#[query]
+fn has_claimed_airdrop(account: Principal) -> bool {
+    AIRDROP.with(|airdrop| airdrop.borrow().as_ref().is_some_and(|airdrop| airdrop.claimed.contains(&account)))
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(AIRDROP.with(|airdrop| airdrop.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let airdrop = candid::decode_one(bytes)?;
+    AIRDROP.with(|state| *state.borrow_mut() = airdrop);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    AIRDROP.with(|airdrop| *airdrop.borrow_mut() = None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, mint, reset_state, test_utils, OWNER};
+    use merkle::MerkleTree;
+
+    #[test]
+    fn test_airdrop_claims_and_reclaim() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        let entries = [(alice, 300u128), (bob, 200u128)];
+        let tree = MerkleTree::new(entries.iter().map(|(account, amount)| merkle::leaf_hash(account, *amount)).collect());
+        let proof = |index: usize| tree.proof(index).iter().map(|hash| hash.to_vec()).collect::<Vec<_>>();
+
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 1000).is_ok());
+        assert!(matches!(publish_airdrop(tree.root().to_vec(), 600, 0), Err(TransferError::InvalidDeadline)));
+        assert!(publish_airdrop(tree.root().to_vec(), 600, 100).is_ok());
+        assert!(matches!(publish_airdrop(tree.root().to_vec(), 1, 100), Err(TransferError::AirdropAlreadyActive)));
+        assert_eq!(balance_of(&owner), 400);
+
+        test_utils::set_caller(alice);
+        assert!(matches!(claim_airdrop(301, proof(0)), Err(TransferError::InvalidProof)));
+        assert!(matches!(claim_airdrop(300, proof(1)), Err(TransferError::InvalidProof)));
+        assert!(claim_airdrop(300, proof(0)).is_ok());
+        assert!(matches!(claim_airdrop(300, proof(0)), Err(TransferError::AlreadyClaimed)));
+        assert_eq!(balance_of(&alice), 300);
+        assert!(has_claimed_airdrop(alice));
+
+        test_utils::set_caller(owner);
+        assert!(matches!(reclaim_airdrop(), Err(TransferError::AirdropNotExpired)));
+
+        test_utils::set_time(100);
+        test_utils::set_caller(bob);
+        assert!(matches!(claim_airdrop(200, proof(1)), Err(TransferError::AirdropExpired)));
+
+        test_utils::set_caller(owner);
+        assert_eq!(reclaim_airdrop().unwrap(), 300);
+        assert_eq!(balance_of(&owner), 700);
+        assert!(get_airdrop().is_none());
+    }
+}
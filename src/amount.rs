@@ -0,0 +1,99 @@
+// Conversion between token amounts written for people ("12.5 ICPT") and base units, given the token's decimals.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AmountError {
+    Empty,
+    InvalidNumber,
+    // More significant fractional digits than the token has decimals; amounts are never rounded.
+    TooPrecise { decimals: u8 },
+    Overflow,
+    WrongSymbol { expected: String },
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountError::Empty => write!(f, "amount is empty"),
+            AmountError::InvalidNumber => write!(f, "amount must be a decimal number such as 12.5"),
+            AmountError::TooPrecise { decimals } => write!(f, "amount has more than {} decimal places", decimals),
+            AmountError::Overflow => write!(f, "amount is too large"),
+            AmountError::WrongSymbol { expected } => write!(f, "amount must be in {}", expected),
+        }
+    }
+}
+
+// Parses `text`, optionally followed by `symbol`, into base units.
+pub fn parse_amount(text: &str, decimals: u8, symbol: &str) -> Result<u128, AmountError> {
+    let mut parts = text.split_whitespace();
+    let number = parts.next().ok_or(AmountError::Empty)?;
+    match (parts.next(), parts.next()) {
+        (None, _) => {}
+        (Some(unit), None) if unit == symbol => {}
+        _ => return Err(AmountError::WrongSymbol { expected: symbol.to_string() }),
+    }
+
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || (number.contains('.') && fraction.is_empty()) {
+        return Err(AmountError::InvalidNumber);
+    }
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > decimals as usize {
+        return Err(AmountError::TooPrecise { decimals });
+    }
+
+    let scale = 10u128.checked_pow(decimals as u32).ok_or(AmountError::Overflow)?;
+    let whole: u128 = whole.parse().map_err(|_| AmountError::Overflow)?;
+    let fraction: u128 = format!("{:0<width$}", fraction, width = decimals as usize)
+        .parse()
+        .unwrap_or(0);
+    whole.checked_mul(scale).and_then(|units| units.checked_add(fraction)).ok_or(AmountError::Overflow)
+}
+
+// Formats base units without trailing fractional zeros, e.g. `1250000000` with 8 decimals as "12.5".
+pub fn format_amount(units: u128, decimals: u8) -> String {
+    let digits = format!("{:0>width$}", units, width = decimals as usize + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals as usize);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("12.5 ICPT", 8, "ICPT"), Ok(1_250_000_000));
+        assert_eq!(parse_amount(" 12 ", 8, "ICPT"), Ok(1_200_000_000));
+        assert_eq!(parse_amount("0.00000001", 8, "ICPT"), Ok(1));
+        assert_eq!(parse_amount("1.000000000", 8, "ICPT"), Ok(100_000_000));
+        assert_eq!(parse_amount("7", 0, "ICPT"), Ok(7));
+
+        assert_eq!(parse_amount("0.000000001", 8, "ICPT"), Err(AmountError::TooPrecise { decimals: 8 }));
+        assert_eq!(parse_amount("12.5 ICP", 8, "ICPT"), Err(AmountError::WrongSymbol { expected: "ICPT".to_string() }));
+        assert_eq!(parse_amount("12.5 ICPT ICPT", 8, "ICPT"), Err(AmountError::WrongSymbol { expected: "ICPT".to_string() }));
+        for invalid in ["-1", "+1", "1.", ".5", "1.2.3", "1e8", "1_000", "ICPT"] {
+            assert_eq!(parse_amount(invalid, 8, "ICPT"), Err(AmountError::InvalidNumber), "{}", invalid);
+        }
+        assert_eq!(parse_amount("", 8, "ICPT"), Err(AmountError::Empty));
+        assert_eq!(parse_amount(&u128::MAX.to_string(), 8, "ICPT"), Err(AmountError::Overflow));
+        assert_eq!(parse_amount("1", 39, "ICPT"), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1_250_000_000, 8), "12.5");
+        assert_eq!(format_amount(1, 8), "0.00000001");
+        assert_eq!(format_amount(0, 8), "0");
+        assert_eq!(format_amount(100_000_000, 8), "1");
+        assert_eq!(format_amount(42, 0), "42");
+        assert_eq!(parse_amount(&format_amount(u128::MAX, 18), 18, "ICPT"), Ok(u128::MAX));
+    }
+}
@@ -0,0 +1,229 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::icp_ledger::Account;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::nfts::is_default_subaccount;
+use crate::{available_balance, balance_of, get_caller, transfer_in_batch, TransferError, TOKEN};
+
+// Keeps a whole batch well within the instruction limit of a single update call.
+pub(crate) const MAX_BATCH_SIZE: u64 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum BatchMode {
+    // Either every entry is transferred or none is.
+    AllOrNothing,
+    // Entries are transferred in order; failed entries are skipped. The total must still be covered up front.
+    BestEffort,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct BatchTransferResult {
+    batch_id: u64,
+    // Block index of each transfer, in the order of the request.
+    results: Vec<Result<u64, TransferError>>,
+}
+
+thread_local! {
+    static NEXT_BATCH_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+fn next_batch_id() -> u64 {
+    NEXT_BATCH_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    })
+}
+
+fn recipient(account: &Account) -> Result<Principal, TransferError> {
+    if !is_default_subaccount(&account.subaccount) {
+        return Err(TransferError::UnsupportedSubaccount);
+    }
+    Ok(account.owner)
+}
+
+fn transfer_all_or_nothing(
+    batch_id: u64,
+    caller: Principal,
+    transfers: &[(Account, u128)],
+) -> Result<Vec<Result<u64, TransferError>>, TransferError> {
+    // Check every entry before paying anyone, so the transfers below cannot fail.
+    let mut incoming: HashMap<Principal, u128> = HashMap::new();
+    for (to, amount) in transfers {
+        if *amount == 0 {
+            return Err(TransferError::InvalidAmount);
+        }
+        let total = incoming.entry(recipient(to)?).or_insert(0);
+        *total = total.checked_add(*amount).ok_or(TransferError::OverflowError)?;
+    }
+    if incoming.iter().any(|(to, amount)| balance_of(to).checked_add(*amount).is_none()) {
+        return Err(TransferError::OverflowError);
+    }
+
+    transfers.iter()
+        .map(|(to, amount)| Ok(Ok(transfer_in_batch(caller, to.owner, *amount, Some(batch_id))?)))
+        .collect()
+}
+
+fn transfer_best_effort(batch_id: u64, caller: Principal, transfers: &[(Account, u128)]) -> Vec<Result<u64, TransferError>> {
+    transfers.iter()
+        .map(|(to, amount)| transfer_in_batch(caller, recipient(to)?, *amount, Some(batch_id)))
+        .collect()
+}
+
+#[update]
+fn batch_transfer(transfers: Vec<(Account, u128)>, mode: BatchMode) -> Result<BatchTransferResult, TransferError> {
+    let caller = get_caller();
+    if transfers.is_empty() {
+        return Err(TransferError::InvalidAmount);
+    }
+    if transfers.len() as u64 > MAX_BATCH_SIZE {
+        return Err(TransferError::BatchTooLarge);
+    }
+    let total = transfers.iter()
+        .try_fold(0u128, |total, (_, amount)| total.checked_add(*amount))
+        .ok_or(TransferError::OverflowError)?;
+    // Each entry pays the transfer fee.
+    let fees = TOKEN.with(|token| token.borrow().fee)
+        .checked_mul(transfers.len() as u128)
+        .ok_or(TransferError::OverflowError)?;
+    if total.checked_add(fees).is_none_or(|total| total > available_balance(&caller)) {
+        return Err(TransferError::InsufficientBalance);
+    }
+
+    let batch_id = next_batch_id();
+    let results = match mode {
+        BatchMode::AllOrNothing => transfer_all_or_nothing(batch_id, caller, &transfers)?,
+        BatchMode::BestEffort => transfer_best_effort(batch_id, caller, &transfers),
+    };
+
+    ic_cdk::println!("Batch {} of {} transfers from {:?}", batch_id, transfers.len(), caller);
+    Ok(BatchTransferResult { batch_id, results })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(NEXT_BATCH_ID.with(|next_id| next_id.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let next_id = candid::decode_one(bytes)?;
+    NEXT_BATCH_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    NEXT_BATCH_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_metadata, get_transfer_history, mint, reset_state, test_utils, MetadataValue, OWNER};
+
+    fn setup() -> (Principal, Principal, Principal) {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 100).is_ok());
+        (owner, alice, bob)
+    }
+
+    fn entries(transfers: &[(Principal, u128)]) -> Vec<(Account, u128)> {
+        transfers.iter().map(|(to, amount)| (Account::new(*to), *amount)).collect()
+    }
+
+    #[test]
+    fn test_all_or_nothing() {
+        let (owner, alice, bob) = setup();
+
+        assert!(matches!(
+            batch_transfer(entries(&[(alice, 60), (bob, 60)]), BatchMode::AllOrNothing),
+            Err(TransferError::InsufficientBalance)
+        ));
+        assert!(matches!(
+            batch_transfer(entries(&[(alice, 10), (bob, 0)]), BatchMode::AllOrNothing),
+            Err(TransferError::InvalidAmount)
+        ));
+        assert_eq!(balance_of(&owner), 100);
+
+        let result = batch_transfer(entries(&[(alice, 60), (bob, 30)]), BatchMode::AllOrNothing).unwrap();
+        assert_eq!(result.results.iter().map(|r| *r.as_ref().unwrap()).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!((balance_of(&owner), balance_of(&alice), balance_of(&bob)), (10, 60, 30));
+
+        let batch_ids: Vec<Option<u64>> = get_transfer_history().iter().map(|event| event.batch_id).collect();
+        assert_eq!(batch_ids, vec![None, Some(result.batch_id), Some(result.batch_id)]);
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let (owner, alice, bob) = setup();
+
+        // The total is checked before any entry is paid, so an uncovered batch pays nobody.
+        assert!(matches!(
+            batch_transfer(entries(&[(alice, 60), (bob, 60), (bob, 40)]), BatchMode::BestEffort),
+            Err(TransferError::InsufficientBalance)
+        ));
+        assert_eq!(balance_of(&owner), 100);
+
+        let result = batch_transfer(entries(&[(alice, 60), (bob, 0), (bob, 40)]), BatchMode::BestEffort).unwrap();
+        assert!(result.results[0].is_ok());
+        assert!(matches!(result.results[1], Err(TransferError::InvalidAmount)));
+        assert!(result.results[2].is_ok());
+        assert_eq!((balance_of(&owner), balance_of(&alice), balance_of(&bob)), (0, 60, 40));
+    }
+
+    #[test]
+    fn test_batch_fees() {
+        let (owner, alice, bob) = setup();
+        assert!(crate::set_transfer_fee(5).is_ok());
+
+        // Every entry pays the fee, and the fees count towards the total checked up front.
+        assert!(matches!(
+            batch_transfer(entries(&[(alice, 50), (bob, 45)]), BatchMode::AllOrNothing),
+            Err(TransferError::InsufficientBalance)
+        ));
+        assert!(batch_transfer(entries(&[(alice, 50), (bob, 40)]), BatchMode::AllOrNothing).is_ok());
+        assert_eq!(balance_of(&owner), 0);
+
+        test_utils::set_caller(alice);
+        let result = batch_transfer(entries(&[(bob, 20), (bob, 30)]), BatchMode::BestEffort);
+        assert!(matches!(result, Err(TransferError::InsufficientBalance)));
+        let result = batch_transfer(entries(&[(bob, 20), (bob, 20)]), BatchMode::BestEffort).unwrap();
+        assert!(result.results.iter().all(|r| r.is_ok()));
+        assert_eq!((balance_of(&alice), balance_of(&bob)), (0, 80));
+    }
+
+    #[test]
+    fn test_subaccounts_rejected() {
+        let (owner, alice, bob) = setup();
+        let subaccount = Account { owner: bob, subaccount: Some(vec![1; 32]) };
+        let default = Account { owner: bob, subaccount: Some(vec![0; 32]) };
+
+        let transfers = vec![(Account::new(alice), 10), (subaccount.clone(), 10)];
+        assert!(matches!(
+            batch_transfer(transfers.clone(), BatchMode::AllOrNothing),
+            Err(TransferError::UnsupportedSubaccount)
+        ));
+        assert_eq!(balance_of(&owner), 100);
+        let result = batch_transfer(transfers, BatchMode::BestEffort).unwrap();
+        assert!(matches!(result.results[1], Err(TransferError::UnsupportedSubaccount)));
+        assert!(batch_transfer(vec![(default, 10)], BatchMode::AllOrNothing).is_ok());
+        assert_eq!((balance_of(&alice), balance_of(&bob)), (10, 10));
+    }
+
+    #[test]
+    fn test_batch_size_cap() {
+        let (_, alice, _) = setup();
+
+        let transfers = vec![(Account::new(alice), 1); MAX_BATCH_SIZE as usize + 1];
+        assert!(matches!(batch_transfer(transfers, BatchMode::BestEffort), Err(TransferError::BatchTooLarge)));
+        assert!(get_metadata().contains(&("max_batch_size".to_string(), MetadataValue::Nat(MAX_BATCH_SIZE.into()))));
+    }
+}
@@ -0,0 +1,127 @@
+// Builds the Merkle tree for an airdrop from a CSV file of `principal,amount` lines.
+//
+// Usage: cargo run --bin airdrop_tree -- <recipients.csv>
+//
+// Prints the root to pass to `publish_airdrop`, followed by one `principal,amount,proof` line per recipient, where
+// the proof is a `;`-separated list of hex hashes for `claim_airdrop`.
+
+use candid::Principal;
+use icp_token_wallet::merkle::{leaf_hash, Hash, MerkleTree};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::process;
+
+fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_line(line: &str) -> Result<(Principal, u128), String> {
+    let (account, amount) = line.split_once(',').ok_or("expected `principal,amount`")?;
+    let account = Principal::from_text(account.trim()).map_err(|err| err.to_string())?;
+    let amount = amount.trim().parse::<u128>().map_err(|err| err.to_string())?;
+    Ok((account, amount))
+}
+
+// Principal text is dash-separated groups of lowercase base32; a header's first column is not.
+fn looks_like_principal(text: &str) -> bool {
+    text.contains('-') && text.chars().all(|c| matches!(c, 'a'..='z' | '2'..='7' | '-'))
+}
+
+fn is_header(line: &str) -> bool {
+    let first = line.split(',').next().unwrap_or_default().trim();
+    !looks_like_principal(first)
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<(Principal, u128)>, String> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+    let mut first = true;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // Only the first row may be a header, and only if it does not start with a principal.
+        if std::mem::take(&mut first) && is_header(line) {
+            continue;
+        }
+        match parse_line(line) {
+            // Each recipient can only claim once, so a second line would lock its amount in the pool.
+            Ok((account, _)) if !seen.insert(account) => {
+                return Err(format!("line {}: duplicate recipient {}", number + 1, account));
+            }
+            Ok(entry) => entries.push(entry),
+            Err(err) => return Err(format!("line {}: {}", number + 1, err)),
+        }
+    }
+    if entries.is_empty() {
+        return Err("no recipients found".to_string());
+    }
+    Ok(entries)
+}
+
+fn total(entries: &[(Principal, u128)]) -> Result<u128, String> {
+    entries
+        .iter()
+        .try_fold(0u128, |total, (_, amount)| total.checked_add(*amount))
+        .ok_or_else(|| "total amount overflows".to_string())
+}
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| {
+        eprintln!("Usage: airdrop_tree <recipients.csv>");
+        process::exit(2);
+    });
+    let contents = fs::read_to_string(&path).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path, err);
+        process::exit(1);
+    });
+    let entries = parse_csv(&contents).unwrap_or_else(|err| {
+        eprintln!("Invalid CSV: {}", err);
+        process::exit(1);
+    });
+
+    let total = total(&entries).unwrap_or_else(|err| {
+        eprintln!("Invalid CSV: {}", err);
+        process::exit(1);
+    });
+    let tree = MerkleTree::new(entries.iter().map(|(account, amount)| leaf_hash(account, *amount)).collect());
+
+    println!("root,{}", to_hex(&tree.root()));
+    println!("total,{}", total);
+    for (index, (account, amount)) in entries.iter().enumerate() {
+        let proof: Vec<String> = tree.proof(index).iter().map(to_hex).collect();
+        println!("{},{},{}", account, amount, proof.join(";"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+    #[test]
+    fn test_parse_csv() {
+        let entries = parse_csv(&format!("principal,amount\n{},100\n\naaaaa-aa,5\n", ALICE)).unwrap();
+        assert_eq!(entries, vec![(Principal::from_text(ALICE).unwrap(), 100), (Principal::from_text("aaaaa-aa").unwrap(), 5)]);
+
+        // A malformed first row that starts with a principal is reported, not skipped as a header.
+        assert_eq!(parse_csv(&format!("{},ten\naaaaa-aa,5", ALICE)).unwrap_err(), "line 1: invalid digit found in string");
+        assert!(parse_csv("rrkah-fqaaa-aaaaa-aaaaq-caj,10\naaaaa-aa,5").unwrap_err().starts_with("line 1:"));
+        // Only the first row can be a header.
+        assert!(parse_csv("aaaaa-aa,5\nprincipal,amount").unwrap_err().starts_with("line 2:"));
+        assert_eq!(parse_csv(&format!("{},1\n{},2", ALICE, ALICE)).unwrap_err(), format!("line 2: duplicate recipient {}", ALICE));
+        assert_eq!(parse_csv("principal,amount\n").unwrap_err(), "no recipients found");
+    }
+
+    #[test]
+    fn test_total() {
+        let entries = parse_csv(&format!("{},{}\naaaaa-aa,1", ALICE, u128::MAX - 1)).unwrap();
+        assert_eq!(total(&entries), Ok(u128::MAX));
+
+        let entries = parse_csv(&format!("{},{}\naaaaa-aa,2", ALICE, u128::MAX - 1)).unwrap();
+        assert_eq!(total(&entries).unwrap_err(), "total amount overflows");
+    }
+}
@@ -0,0 +1,160 @@
+// Minimal stand-in for the ICP ledger, for exercising `wrap` and `unwrap` on a local replica. It implements the
+// ICRC-1 and ICRC-2 methods the wrapping module uses, `query_blocks` for deposit detection, and `mint_icp` to fund
+// test accounts. There is no archiving, deduplication or allowance expiry, and blocks carry no parent hash.
+//
+// Deploy it as a canister built from this binary and point the token canister at it with `set_icp_ledger`.
+
+use candid::Nat;
+use ic_cdk_macros::*;
+use icp_token_wallet::account_identifier::AccountIdentifier;
+use icp_token_wallet::icp_ledger::{
+    Account, ApproveArgs, ApproveError, Block, GetBlocksArgs, Operation, QueryBlocksResponse, TimeStamp, Tokens,
+    Transaction, TransferArg, TransferError, TransferFromArgs, TransferFromError,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const FEE: u128 = 10_000;
+
+thread_local! {
+    static BALANCES: RefCell<BTreeMap<Account, u128>> = const { RefCell::new(BTreeMap::new()) };
+    // Keyed by (owner, spender).
+    static ALLOWANCES: RefCell<BTreeMap<(Account, Account), u128>> = const { RefCell::new(BTreeMap::new()) };
+    static BLOCKS: RefCell<Vec<Block>> = const { RefCell::new(Vec::new()) };
+}
+
+fn to_u128(amount: &Nat) -> u128 {
+    amount.0.clone().try_into().unwrap_or(u128::MAX)
+}
+
+fn balance_of(account: &Account) -> u128 {
+    BALANCES.with(|balances| balances.borrow().get(account).cloned().unwrap_or(0))
+}
+
+// Blocks name accounts by their legacy account identifier, as on the ICP ledger.
+fn account_id(account: &Account) -> Vec<u8> {
+    let subaccount: Option<[u8; 32]> = account.subaccount.as_ref().and_then(|subaccount| subaccount.as_slice().try_into().ok());
+    AccountIdentifier::new(&account.owner, subaccount.as_ref()).as_bytes().to_vec()
+}
+
+fn tokens(amount: &Nat) -> Tokens {
+    Tokens { e8s: amount.0.clone().try_into().unwrap_or(u64::MAX) }
+}
+
+// Appends to the log and returns the block index.
+fn log(operation: Operation) -> Nat {
+    let now = TimeStamp { timestamp_nanos: ic_cdk::api::time() };
+    let transaction = Transaction { memo: 0, icrc1_memo: None, operation: Some(operation), created_at_time: now };
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        blocks.push(Block { parent_hash: None, transaction, timestamp: now });
+        Nat::from(blocks.len() - 1)
+    })
+}
+
+fn log_transfer(from: &Account, to: &Account, amount: &Nat) -> Nat {
+    log(Operation::Transfer {
+        from: account_id(from),
+        to: account_id(to),
+        amount: tokens(amount),
+        fee: Tokens { e8s: FEE as u64 },
+    })
+}
+
+// Moves `amount` plus the fee out of `from`; the fee is burned. Returns the sender's balance if it is too low.
+fn move_funds(from: &Account, to: &Account, amount: u128) -> Result<(), u128> {
+    let balance = balance_of(from);
+    if balance < amount.saturating_add(FEE) {
+        return Err(balance);
+    }
+    BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        balances.insert(from.clone(), balance - amount - FEE);
+        *balances.entry(to.clone()).or_insert(0) += amount;
+    });
+    Ok(())
+}
+
+fn check_fee(fee: &Option<Nat>) -> bool {
+    fee.as_ref().is_none_or(|fee| to_u128(fee) == FEE)
+}
+
+#[query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    Nat::from(balance_of(&account))
+}
+
+#[update]
+fn icrc1_transfer(args: TransferArg) -> Result<Nat, TransferError> {
+    if !check_fee(&args.fee) {
+        return Err(TransferError::BadFee { expected_fee: Nat::from(FEE) });
+    }
+    let from = Account { owner: ic_cdk::caller(), subaccount: args.from_subaccount };
+    move_funds(&from, &args.to, to_u128(&args.amount))
+        .map_err(|balance| TransferError::InsufficientFunds { balance: Nat::from(balance) })?;
+    Ok(log_transfer(&from, &args.to, &args.amount))
+}
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    if !check_fee(&args.fee) {
+        return Err(ApproveError::BadFee { expected_fee: Nat::from(FEE) });
+    }
+    let owner = Account { owner: ic_cdk::caller(), subaccount: args.from_subaccount };
+    let balance = balance_of(&owner);
+    if balance < FEE {
+        return Err(ApproveError::InsufficientFunds { balance: Nat::from(balance) });
+    }
+    BALANCES.with(|balances| balances.borrow_mut().insert(owner.clone(), balance - FEE));
+    let approve = Operation::Approve { from: account_id(&owner), spender: account_id(&args.spender) };
+    ALLOWANCES.with(|allowances| allowances.borrow_mut().insert((owner, args.spender), to_u128(&args.amount)));
+    Ok(log(approve))
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    if !check_fee(&args.fee) {
+        return Err(TransferFromError::BadFee { expected_fee: Nat::from(FEE) });
+    }
+    let spender = Account { owner: ic_cdk::caller(), subaccount: args.spender_subaccount };
+    let key = (args.from.clone(), spender.clone());
+    let amount = to_u128(&args.amount);
+    let allowance = ALLOWANCES.with(|allowances| allowances.borrow().get(&key).cloned().unwrap_or(0));
+    if allowance < amount.saturating_add(FEE) {
+        return Err(TransferFromError::InsufficientAllowance { allowance: Nat::from(allowance) });
+    }
+    move_funds(&args.from, &args.to, amount)
+        .map_err(|balance| TransferFromError::InsufficientFunds { balance: Nat::from(balance) })?;
+    ALLOWANCES.with(|allowances| allowances.borrow_mut().insert(key, allowance - amount - FEE));
+    Ok(log_transfer(&args.from, &args.to, &args.amount))
+}
+
+// Test helper: creates ICP out of thin air.
+#[update]
+fn mint_icp(account: Account, amount: Nat) -> Nat {
+    BALANCES.with(|balances| *balances.borrow_mut().entry(account.clone()).or_insert(0) += to_u128(&amount));
+    log(Operation::Mint { to: account_id(&account), amount: tokens(&amount) })
+}
+
+#[query]
+fn query_blocks(args: GetBlocksArgs) -> QueryBlocksResponse {
+    BLOCKS.with(|blocks| {
+        let blocks = blocks.borrow();
+        let start = (args.start as usize).min(blocks.len());
+        let end = start.saturating_add(args.length as usize).min(blocks.len());
+        QueryBlocksResponse {
+            chain_length: blocks.len() as u64,
+            certificate: None,
+            blocks: blocks[start..end].to_vec(),
+            first_block_index: start as u64,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+fn main() {}
@@ -0,0 +1,442 @@
+// ICRC-21 consent messages: a human-readable description of a call that signers such as hardware wallets show
+// before the user approves it. Amounts are rendered with the symbol and decimals of the built-in token.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::amount::format_amount;
+
+use crate::nfts::{ApprovalInfo, ApproveCollectionArg, ApproveTokenArg};
+use crate::{get_token_info, Token};
+
+pub(crate) const STANDARDS: &[(&str, &str)] = &[(
+    "ICRC-21",
+    "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/ICRC-21/icrc_21_consent_msg.md",
+)];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct ConsentMessageMetadata {
+    // BCP 47 tag such as "en" or "de-CH"; answers carry the language actually used.
+    language: String,
+    utc_offset_minutes: Option<i16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum DeviceSpec {
+    GenericDisplay,
+    LineDisplay { characters_per_line: u16, lines_per_page: u16 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ConsentMessageSpec {
+    metadata: ConsentMessageMetadata,
+    device_spec: Option<DeviceSpec>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ConsentMessageRequest {
+    method: String,
+    // Candid-encoded arguments of the call.
+    arg: Vec<u8>,
+    user_preferences: ConsentMessageSpec,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct LineDisplayPage {
+    lines: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum ConsentMessage {
+    // Markdown.
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<LineDisplayPage> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct ConsentInfo {
+    consent_message: ConsentMessage,
+    metadata: ConsentMessageMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct ErrorInfo {
+    description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum ConsentError {
+    UnsupportedCanisterCall(ErrorInfo),
+    ConsentMessageUnavailable(ErrorInfo),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Language {
+    En,
+    De,
+    Fr,
+    Es,
+}
+
+impl Language {
+    // Falls back to English for languages without translations.
+    fn from_tag(tag: &str) -> Language {
+        let primary = tag.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+        match primary.as_str() {
+            "de" => Language::De,
+            "fr" => Language::Fr,
+            "es" => Language::Es,
+            _ => Language::En,
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::De => "de",
+            Language::Fr => "fr",
+            Language::Es => "es",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Title {
+    Transfer,
+    Mint,
+    Burn,
+    ApproveTokens,
+    ApproveCollection,
+    ChangeOwner,
+}
+
+#[derive(Clone, Copy)]
+enum Label {
+    Amount,
+    To,
+    Fee,
+    Nft,
+    Spender,
+    Expires,
+    Never,
+    NewOwner,
+}
+
+fn title(language: Language, title: Title, symbol: &str) -> String {
+    let template = match (language, title) {
+        (Language::En, Title::Transfer) => "Transfer {}",
+        (Language::En, Title::Mint) => "Mint {}",
+        (Language::En, Title::Burn) => "Burn {}",
+        (Language::En, Title::ApproveTokens) => "Approve NFT transfers",
+        (Language::En, Title::ApproveCollection) => "Approve transfers of all your NFTs",
+        (Language::En, Title::ChangeOwner) => "Change {} owner",
+        (Language::De, Title::Transfer) => "{} überweisen",
+        (Language::De, Title::Mint) => "{} prägen",
+        (Language::De, Title::Burn) => "{} verbrennen",
+        (Language::De, Title::ApproveTokens) => "NFT-Übertragungen erlauben",
+        (Language::De, Title::ApproveCollection) => "Übertragung aller Ihrer NFTs erlauben",
+        (Language::De, Title::ChangeOwner) => "Eigentümer von {} ändern",
+        (Language::Fr, Title::Transfer) => "Transférer des {}",
+        (Language::Fr, Title::Mint) => "Émettre des {}",
+        (Language::Fr, Title::Burn) => "Brûler des {}",
+        (Language::Fr, Title::ApproveTokens) => "Autoriser des transferts de NFT",
+        (Language::Fr, Title::ApproveCollection) => "Autoriser le transfert de tous vos NFT",
+        (Language::Fr, Title::ChangeOwner) => "Changer le propriétaire de {}",
+        (Language::Es, Title::Transfer) => "Transferir {}",
+        (Language::Es, Title::Mint) => "Acuñar {}",
+        (Language::Es, Title::Burn) => "Quemar {}",
+        (Language::Es, Title::ApproveTokens) => "Aprobar transferencias de NFT",
+        (Language::Es, Title::ApproveCollection) => "Aprobar transferencias de todos sus NFT",
+        (Language::Es, Title::ChangeOwner) => "Cambiar el propietario de {}",
+    };
+    template.replace("{}", symbol)
+}
+
+fn label(language: Language, label: Label) -> &'static str {
+    match (language, label) {
+        (Language::En, Label::Amount) => "Amount",
+        (Language::En, Label::To) => "To",
+        (Language::En, Label::Fee) => "Fee",
+        (Language::En, Label::Nft) => "NFT",
+        (Language::En, Label::Spender) => "Spender",
+        (Language::En, Label::Expires) => "Expires",
+        (Language::En, Label::Never) => "Never",
+        (Language::En, Label::NewOwner) => "New owner",
+        (Language::De, Label::Amount) => "Betrag",
+        (Language::De, Label::To) => "An",
+        (Language::De, Label::Fee) => "Gebühr",
+        (Language::De, Label::Nft) => "NFT",
+        (Language::De, Label::Spender) => "Berechtigter",
+        (Language::De, Label::Expires) => "Gültig bis",
+        (Language::De, Label::Never) => "Unbegrenzt",
+        (Language::De, Label::NewOwner) => "Neuer Eigentümer",
+        (Language::Fr, Label::Amount) => "Montant",
+        (Language::Fr, Label::To) => "Destinataire",
+        (Language::Fr, Label::Fee) => "Frais",
+        (Language::Fr, Label::Nft) => "NFT",
+        (Language::Fr, Label::Spender) => "Bénéficiaire",
+        (Language::Fr, Label::Expires) => "Expire le",
+        (Language::Fr, Label::Never) => "Jamais",
+        (Language::Fr, Label::NewOwner) => "Nouveau propriétaire",
+        (Language::Es, Label::Amount) => "Importe",
+        (Language::Es, Label::To) => "Destinatario",
+        (Language::Es, Label::Fee) => "Comisión",
+        (Language::Es, Label::Nft) => "NFT",
+        (Language::Es, Label::Spender) => "Autorizado",
+        (Language::Es, Label::Expires) => "Vence",
+        (Language::Es, Label::Never) => "Nunca",
+        (Language::Es, Label::NewOwner) => "Nuevo propietario",
+    }
+}
+
+// The languages other than English write a decimal comma.
+fn format_token_amount(language: Language, units: u128, token: &Token) -> String {
+    let number = format_amount(units, token.decimals);
+    let number = if language == Language::En { number } else { number.replace('.', ",") };
+    format!("{} {}", number, token.symbol)
+}
+
+// Days since 1970-01-01 to a (year, month, day) date, after Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// A nanosecond timestamp as "2023-11-14 22:13 UTC" in the user's time zone.
+fn format_time(timestamp: u64, utc_offset_minutes: Option<i16>) -> String {
+    let offset = i64::from(utc_offset_minutes.unwrap_or(0));
+    let minutes = (timestamp / 60_000_000_000) as i64 + offset;
+    let (year, month, day) = civil_from_days(minutes.div_euclid(24 * 60));
+    let minute_of_day = minutes.rem_euclid(24 * 60);
+    let zone = match offset {
+        0 => "UTC".to_string(),
+        _ => format!("UTC{}{:02}:{:02}", if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60),
+    };
+    format!("{:04}-{:02}-{:02} {:02}:{:02} {}", year, month, day, minute_of_day / 60, minute_of_day % 60, zone)
+}
+
+// Splits `text` into lines of at most `width` characters, breaking words only when they do not fit on a line.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let chars: Vec<char> = word.chars().collect();
+        for chunk in chars.chunks(width) {
+            let length = line.chars().count();
+            if length > 0 && length + 1 + chunk.len() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.extend(chunk);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+struct Message {
+    title: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    fn generic_display(&self) -> String {
+        let mut text = format!("# {}", self.title);
+        for (label, value) in &self.fields {
+            text.push_str(&format!("\n\n**{}:** {}", label, value));
+        }
+        text
+    }
+
+    // Each label starts a new line, so values are never split across a label.
+    fn line_display(&self, characters_per_line: usize, lines_per_page: usize) -> Vec<LineDisplayPage> {
+        let mut lines = wrap(&self.title, characters_per_line);
+        for (label, value) in &self.fields {
+            lines.extend(wrap(&format!("{}:", label), characters_per_line));
+            lines.extend(wrap(value, characters_per_line));
+        }
+        lines.chunks(lines_per_page).map(|lines| LineDisplayPage { lines: lines.to_vec() }).collect()
+    }
+}
+
+fn approval_fields(language: Language, approval: &ApprovalInfo, utc_offset_minutes: Option<i16>) -> Vec<(&'static str, String)> {
+    let expires = approval.expires_at
+        .map_or(label(language, Label::Never).to_string(), |expires_at| format_time(expires_at, utc_offset_minutes));
+    vec![
+        (label(language, Label::Spender), approval.spender.to_string()),
+        (label(language, Label::Expires), expires),
+    ]
+}
+
+fn message(method: &str, arg: &[u8], language: Language, utc_offset_minutes: Option<i16>) -> Result<Message, ConsentError> {
+    let token = get_token_info();
+    let undecodable = |err: candid::Error| {
+        ConsentError::UnsupportedCanisterCall(ErrorInfo { description: format!("invalid arguments for {}: {}", method, err) })
+    };
+    let amount = |units| (label(language, Label::Amount), format_token_amount(language, units, &token));
+
+    let (title_of, fields) = match method {
+        "transfer" | "mint" => {
+            let (to, units): (Principal, u128) = candid::decode_args(arg).map_err(undecodable)?;
+            let mut fields = vec![amount(units), (label(language, Label::To), to.to_text())];
+            if method == "mint" {
+                (Title::Mint, fields)
+            } else {
+                fields.push((label(language, Label::Fee), format_token_amount(language, token.fee, &token)));
+                (Title::Transfer, fields)
+            }
+        }
+        "burn" => {
+            let (units,): (u128,) = candid::decode_args(arg).map_err(undecodable)?;
+            (Title::Burn, vec![amount(units)])
+        }
+        "icrc37_approve_tokens" => {
+            let (args,): (Vec<ApproveTokenArg>,) = candid::decode_args(arg).map_err(undecodable)?;
+            let fields = args.iter()
+                .flat_map(|arg| {
+                    let nft = (label(language, Label::Nft), format!("#{}", arg.token_id.0));
+                    std::iter::once(nft).chain(approval_fields(language, &arg.approval_info, utc_offset_minutes))
+                })
+                .collect();
+            (Title::ApproveTokens, fields)
+        }
+        "icrc37_approve_collection" => {
+            let (args,): (Vec<ApproveCollectionArg>,) = candid::decode_args(arg).map_err(undecodable)?;
+            let fields = args.iter()
+                .flat_map(|arg| approval_fields(language, &arg.approval_info, utc_offset_minutes))
+                .collect();
+            (Title::ApproveCollection, fields)
+        }
+        "change_owner" => {
+            let (new_owner,): (Principal,) = candid::decode_args(arg).map_err(undecodable)?;
+            (Title::ChangeOwner, vec![(label(language, Label::NewOwner), new_owner.to_text())])
+        }
+        _ => {
+            let description = format!("no consent message for {}", method);
+            return Err(ConsentError::UnsupportedCanisterCall(ErrorInfo { description }));
+        }
+    };
+    Ok(Message { title: title(language, title_of, &token.symbol), fields })
+}
+
+#[update]
+fn icrc21_canister_call_consent_message(request: ConsentMessageRequest) -> Result<ConsentInfo, ConsentError> {
+    let preferences = request.user_preferences;
+    let language = Language::from_tag(&preferences.metadata.language);
+    let utc_offset_minutes = preferences.metadata.utc_offset_minutes;
+    let message = message(&request.method, &request.arg, language, utc_offset_minutes)?;
+
+    let consent_message = match preferences.device_spec {
+        None | Some(DeviceSpec::GenericDisplay) => ConsentMessage::GenericDisplayMessage(message.generic_display()),
+        Some(DeviceSpec::LineDisplay { characters_per_line, lines_per_page }) => {
+            if characters_per_line == 0 || lines_per_page == 0 {
+                let description = "line display needs at least one line of one character".to_string();
+                return Err(ConsentError::ConsentMessageUnavailable(ErrorInfo { description }));
+            }
+            let pages = message.line_display(characters_per_line as usize, lines_per_page as usize);
+            ConsentMessage::LineDisplayMessage { pages }
+        }
+    };
+    Ok(ConsentInfo {
+        consent_message,
+        metadata: ConsentMessageMetadata { language: language.tag().to_string(), utc_offset_minutes },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reset_state, TOKEN};
+    use candid::Nat;
+    use icp_token_wallet::icp_ledger::Account;
+
+    fn request(method: &str, arg: Vec<u8>, language: &str, device_spec: Option<DeviceSpec>) -> ConsentMessageRequest {
+        ConsentMessageRequest {
+            method: method.to_string(),
+            arg,
+            user_preferences: ConsentMessageSpec {
+                metadata: ConsentMessageMetadata { language: language.to_string(), utc_offset_minutes: Some(60) },
+                device_spec,
+            },
+        }
+    }
+
+    #[test]
+    fn test_generic_display() {
+        reset_state();
+        let to = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let arg = candid::encode_args((to, 1_250_000_000u128)).unwrap();
+
+        let info = icrc21_canister_call_consent_message(request("transfer", arg.clone(), "en-US", None)).unwrap();
+        assert_eq!(info.metadata.language, "en");
+        assert_eq!(
+            info.consent_message,
+            ConsentMessage::GenericDisplayMessage(
+                "# Transfer ICPT\n\n**Amount:** 12.5 ICPT\n\n**To:** rrkah-fqaaa-aaaaa-aaaaq-cai\n\n**Fee:** 0 ICPT".to_string()
+            )
+        );
+        TOKEN.with(|token| token.borrow_mut().fee = 10_000);
+        let info = icrc21_canister_call_consent_message(request("transfer", arg, "de-CH", None)).unwrap();
+        assert_eq!(info.metadata.language, "de");
+        assert!(matches!(info.consent_message, ConsentMessage::GenericDisplayMessage(text)
+            if text.contains("**Betrag:** 12,5 ICPT") && text.contains("**Gebühr:** 0,0001 ICPT")));
+        let info = icrc21_canister_call_consent_message(request("burn", candid::encode_args((5u128,)).unwrap(), "ja", None)).unwrap();
+        assert_eq!(info.metadata.language, "en");
+
+        let approval = ApproveTokenArg {
+            token_id: Nat::from(3u64),
+            approval_info: ApprovalInfo {
+                spender: Account::new(to),
+                from_subaccount: None,
+                expires_at: Some(1_700_000_000_000_000_000),
+                memo: None,
+                created_at_time: 0,
+            },
+        };
+        let arg = candid::encode_args((vec![approval],)).unwrap();
+        let info = icrc21_canister_call_consent_message(request("icrc37_approve_tokens", arg, "fr", None)).unwrap();
+        assert!(matches!(info.consent_message, ConsentMessage::GenericDisplayMessage(text)
+            if text.contains("**NFT:** #3") && text.contains("**Expire le:** 2023-11-14 23:13 UTC+01:00")));
+
+        assert!(matches!(
+            icrc21_canister_call_consent_message(request("lock", candid::encode_args((1u128, 2u64)).unwrap(), "en", None)),
+            Err(ConsentError::UnsupportedCanisterCall(_))
+        ));
+        assert!(matches!(
+            icrc21_canister_call_consent_message(request("transfer", candid::encode_args((to,)).unwrap(), "en", None)),
+            Err(ConsentError::UnsupportedCanisterCall(_))
+        ));
+    }
+
+    #[test]
+    fn test_line_display() {
+        reset_state();
+        let new_owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let arg = candid::encode_args((new_owner,)).unwrap();
+        let spec = DeviceSpec::LineDisplay { characters_per_line: 16, lines_per_page: 2 };
+
+        let info = icrc21_canister_call_consent_message(request("change_owner", arg.clone(), "en", Some(spec))).unwrap();
+        let pages = vec![
+            LineDisplayPage { lines: vec!["Change ICPT".to_string(), "owner".to_string()] },
+            LineDisplayPage { lines: vec!["New owner:".to_string(), "rrkah-fqaaa-aaaa".to_string()] },
+            LineDisplayPage { lines: vec!["a-aaaaq-cai".to_string()] },
+        ];
+        assert_eq!(info.consent_message, ConsentMessage::LineDisplayMessage { pages });
+
+        let spec = DeviceSpec::LineDisplay { characters_per_line: 0, lines_per_page: 2 };
+        assert!(matches!(
+            icrc21_canister_call_consent_message(request("change_owner", arg, "en", Some(spec))),
+            Err(ConsentError::ConsentMessageUnavailable(_))
+        ));
+    }
+}
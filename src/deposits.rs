@@ -0,0 +1,515 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::account_identifier::AccountIdentifier;
+use icp_token_wallet::icp_ledger::{
+    self, principal_subaccount, Account, Block, GetBlocksArgs, Operation, QueryArchiveFn, QueryArchiveResult,
+    QueryBlocksResponse, TransferArg,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::factory::call_failed;
+use crate::wrap::{self, block_index, LedgerCanister};
+use crate::{get_caller, is_owner, TransferError};
+
+// Ledger blocks scanned per sweep.
+const MAX_BLOCKS_PER_SWEEP: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum DepositStatus {
+    // Credited, but the funds are still in the deposit subaccount.
+    Credited,
+    // Moved into the reserves in `sweep_block_index` on the ICP ledger.
+    Swept { sweep_block_index: u64 },
+    // Does not cover the ledger fee of moving it into the reserves, so nothing was credited.
+    BelowFee,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Deposit {
+    source_block_index: u64,
+    user: Principal,
+    amount: u128,
+    // `amount` minus the ledger fee for the sweep.
+    credited: u128,
+    // WICP block index of the credit.
+    block_index: Option<u64>,
+    status: DepositStatus,
+}
+
+thread_local! {
+    // Keyed by the deposit's block index on the ICP ledger, so a block is credited at most once however often it
+    // is scanned.
+    static DEPOSITS: RefCell<BTreeMap<u64, Deposit>> = const { RefCell::new(BTreeMap::new()) };
+    // Next ICP ledger block to scan. The owner moves it past the ledger's history before the first sweep.
+    static NEXT_SOURCE_BLOCK: RefCell<u64> = const { RefCell::new(0) };
+    // Account identifier of every registered deposit address, since blocks only name the hash.
+    static DEPOSIT_ADDRESSES: RefCell<HashMap<[u8; 32], Principal>> = RefCell::new(HashMap::new());
+    static SWEEPING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// The ledger calls sweeping needs, behind a trait so the tests can run against an in-memory ledger.
+trait DepositLedger {
+    fn fee(&self) -> u128;
+    async fn query_blocks(&self, start: u64, length: u64) -> Result<QueryBlocksResponse, TransferError>;
+    // Fetches archived blocks through the callback of an `ArchivedBlocksRange`.
+    async fn query_archive(&self, callback: &QueryArchiveFn, start: u64, length: u64) -> Result<Vec<Block>, TransferError>;
+    // Moves `amount` from a deposit subaccount into the reserves; the subaccount also pays the fee.
+    async fn sweep(&self, subaccount: [u8; 32], amount: u128) -> Result<u64, TransferError>;
+}
+
+impl DepositLedger for LedgerCanister {
+    fn fee(&self) -> u128 {
+        self.0.fee
+    }
+
+    async fn query_blocks(&self, start: u64, length: u64) -> Result<QueryBlocksResponse, TransferError> {
+        let (response,): (QueryBlocksResponse,) =
+            ic_cdk::call(self.0.ledger, "query_blocks", (GetBlocksArgs { start, length },)).await.map_err(call_failed)?;
+        Ok(response)
+    }
+
+    async fn query_archive(&self, callback: &QueryArchiveFn, start: u64, length: u64) -> Result<Vec<Block>, TransferError> {
+        let (result,): (QueryArchiveResult,) =
+            ic_cdk::call(callback.0.principal, &callback.0.method, (GetBlocksArgs { start, length },))
+                .await
+                .map_err(call_failed)?;
+        result.map(|range| range.blocks).map_err(|err| TransferError::CanisterCallFailed(format!("{:?}", err)))
+    }
+
+    async fn sweep(&self, subaccount: [u8; 32], amount: u128) -> Result<u64, TransferError> {
+        let args = TransferArg {
+            from_subaccount: Some(subaccount.to_vec()),
+            to: Account::new(ic_cdk::id()),
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(self.0.fee)),
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, icp_ledger::TransferError>,) =
+            ic_cdk::call(self.0.ledger, "icrc1_transfer", (args,)).await.map_err(call_failed)?;
+        result.map(block_index).map_err(|err| TransferError::IcpTransferFailed(format!("{:?}", err)))
+    }
+}
+
+// Only one sweep runs at a time, so two sweeps cannot move the same deposit twice.
+struct SweepGuard;
+
+impl SweepGuard {
+    fn new() -> Result<SweepGuard, TransferError> {
+        if SWEEPING.with(|sweeping| sweeping.replace(true)) {
+            return Err(TransferError::SweepInProgress);
+        }
+        Ok(SweepGuard)
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEPING.with(|sweeping| *sweeping.borrow_mut() = false);
+    }
+}
+
+// Total credited but not yet moved into the reserves.
+pub(crate) fn pending_sweeps() -> u128 {
+    DEPOSITS.with(|deposits| {
+        deposits.borrow()
+            .values()
+            .filter(|deposit| deposit.status == DepositStatus::Credited)
+            .map(|deposit| deposit.credited)
+            .sum()
+    })
+}
+
+fn deposit_account(canister: Principal, user: &Principal) -> Account {
+    Account { owner: canister, subaccount: Some(principal_subaccount(user).to_vec()) }
+}
+
+fn register_deposit_account(canister: Principal, user: Principal) -> Account {
+    let account = deposit_account(canister, &user);
+    let account_identifier = AccountIdentifier::new(&canister, Some(&principal_subaccount(&user)));
+    DEPOSIT_ADDRESSES.with(|addresses| addresses.borrow_mut().insert(*account_identifier.as_bytes(), user));
+    account
+}
+
+// The user and amount of a block paying into a registered deposit address.
+fn deposit_of(block: &Block) -> Option<(Principal, u128)> {
+    let (to, amount) = match block.transaction.operation.as_ref()? {
+        Operation::Transfer { to, amount, .. } | Operation::Mint { to, amount } => (to, amount),
+        _ => return None,
+    };
+    let to: [u8; 32] = to.as_slice().try_into().ok()?;
+    let user = DEPOSIT_ADDRESSES.with(|addresses| addresses.borrow().get(&to).copied())?;
+    Some((user, amount.e8s as u128))
+}
+
+// Up to `length` consecutive blocks from `start`, reading the archived ones through their archive callbacks. Stops
+// early at a short archive reply; the next sweep continues from there.
+async fn fetch_blocks(ledger: &impl DepositLedger, start: u64, length: u64) -> Result<Vec<Block>, TransferError> {
+    let mut response = ledger.query_blocks(start, length).await?;
+    response.archived_blocks.sort_by_key(|range| range.start);
+
+    let mut blocks = Vec::new();
+    let mut next = start;
+    for range in &response.archived_blocks {
+        if range.start != next {
+            return Err(TransferError::CanisterCallFailed(format!("archived blocks {}..{} are missing", next, range.start)));
+        }
+        let archived = ledger.query_archive(&range.callback, range.start, range.length).await?;
+        next += archived.len() as u64;
+        let complete = archived.len() as u64 == range.length;
+        blocks.extend(archived);
+        if !complete {
+            return Ok(blocks);
+        }
+    }
+    if !response.blocks.is_empty() && response.first_block_index != next {
+        return Err(TransferError::CanisterCallFailed(format!(
+            "blocks {}..{} are missing",
+            next, response.first_block_index
+        )));
+    }
+    blocks.extend(response.blocks);
+    Ok(blocks)
+}
+
+// Returns whether the block was new.
+fn credit_deposit(source_block_index: u64, user: Principal, amount: u128, fee: u128) -> Result<bool, TransferError> {
+    if DEPOSITS.with(|deposits| deposits.borrow().contains_key(&source_block_index)) {
+        return Ok(false);
+    }
+    let (credited, block_index, status) = if amount <= fee {
+        (0, None, DepositStatus::BelowFee)
+    } else {
+        (amount - fee, Some(wrap::mint_wrapped(user, amount - fee)?), DepositStatus::Credited)
+    };
+    DEPOSITS.with(|deposits| {
+        deposits.borrow_mut().insert(source_block_index, Deposit {
+            source_block_index,
+            user,
+            amount,
+            credited,
+            block_index,
+            status,
+        });
+    });
+    Ok(true)
+}
+
+async fn sweep_deposits_with(ledger: &impl DepositLedger, canister: Principal) -> Result<Vec<Deposit>, TransferError> {
+    let _guard = SweepGuard::new()?;
+    let start = NEXT_SOURCE_BLOCK.with(|next| *next.borrow());
+    let blocks = fetch_blocks(ledger, start, MAX_BLOCKS_PER_SWEEP).await?;
+
+    let mut new_deposits = Vec::new();
+    for (index, block) in (start..).zip(&blocks) {
+        if let Some((user, amount)) = deposit_of(block) {
+            if credit_deposit(index, user, amount, ledger.fee())? {
+                new_deposits.push(index);
+            }
+        }
+        NEXT_SOURCE_BLOCK.with(|next| *next.borrow_mut() = index + 1);
+    }
+
+    // Also retries deposits a previous sweep failed to move.
+    let unswept: Vec<(u64, Principal, u128)> = DEPOSITS.with(|deposits| {
+        deposits.borrow()
+            .values()
+            .filter(|deposit| deposit.status == DepositStatus::Credited)
+            .map(|deposit| (deposit.source_block_index, deposit.user, deposit.credited))
+            .collect()
+    });
+    for (source_block_index, user, credited) in unswept {
+        match ledger.sweep(principal_subaccount(&user), credited).await {
+            Ok(sweep_block_index) => DEPOSITS.with(|deposits| {
+                if let Some(deposit) = deposits.borrow_mut().get_mut(&source_block_index) {
+                    deposit.status = DepositStatus::Swept { sweep_block_index };
+                }
+            }),
+            Err(err) => ic_cdk::println!(
+                "Failed to sweep deposit {} from {}: {:?}",
+                source_block_index,
+                deposit_account(canister, &user),
+                err
+            ),
+        }
+    }
+
+    Ok(new_deposits.into_iter().filter_map(get_deposit).collect())
+}
+
+// Registers the caller's deposit address so sweeps recognize payments to it, and returns it. Only payments in
+// blocks scanned after the registration are credited.
+#[update]
+fn register_deposit_address() -> Account {
+    register_deposit_account(ic_cdk::id(), get_caller())
+}
+
+// Where `user` sends ICP to have it credited as WICP: a subaccount of this canister derived from the principal.
+// Sweeps only recognize it once `user` has called `register_deposit_address`.
+#[query]
+fn get_deposit_address(user: Principal) -> Account {
+    deposit_account(ic_cdk::id(), &user)
+}
+
+// The deposit address in ICRC-1 textual form, as wallets and block explorers show it.
+#[query]
+fn get_deposit_address_text(user: Principal) -> String {
+    deposit_account(ic_cdk::id(), &user).to_string()
+}
+
+// Scans the ICP ledger for new deposits, credits them minus the ledger fee and moves them into the reserves.
+// Anyone can call it; returns the deposits credited by this call.
+#[update]
+async fn sweep_deposits() -> Result<Vec<Deposit>, TransferError> {
+    sweep_deposits_with(&wrap::ledger()?, ic_cdk::id()).await
+}
+
+// Sets the next ICP ledger block to scan, typically the ledger's chain length when deposits are first enabled.
+// Moving it back rescans blocks without crediting any of them twice.
+#[update]
+fn set_next_deposit_block(block_index: u64) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    if SWEEPING.with(|sweeping| *sweeping.borrow()) {
+        return Err(TransferError::SweepInProgress);
+    }
+    NEXT_SOURCE_BLOCK.with(|next| *next.borrow_mut() = block_index);
+    Ok(())
+}
+
+#[query]
+fn get_next_deposit_block() -> u64 {
+    NEXT_SOURCE_BLOCK.with(|next| *next.borrow())
+}
+
+#[query]
+fn get_deposit(source_block_index: u64) -> Option<Deposit> {
+    DEPOSITS.with(|deposits| deposits.borrow().get(&source_block_index).cloned())
+}
+
+#[query]
+fn get_deposits(user: Principal) -> Vec<Deposit> {
+    DEPOSITS.with(|deposits| deposits.borrow().values().filter(|deposit| deposit.user == user).cloned().collect())
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        DEPOSITS.with(|deposits| deposits.take()),
+        NEXT_SOURCE_BLOCK.with(|next_block| next_block.take()),
+        DEPOSIT_ADDRESSES.with(|addresses| addresses.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (deposits, next_block, addresses) = candid::decode_one(bytes)?;
+    DEPOSITS.with(|state| *state.borrow_mut() = deposits);
+    NEXT_SOURCE_BLOCK.with(|state| *state.borrow_mut() = next_block);
+    DEPOSIT_ADDRESSES.with(|state| *state.borrow_mut() = addresses);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    DEPOSITS.with(|deposits| deposits.borrow_mut().clear());
+    NEXT_SOURCE_BLOCK.with(|next| *next.borrow_mut() = 0);
+    SWEEPING.with(|sweeping| *sweeping.borrow_mut() = false);
+    DEPOSIT_ADDRESSES.with(|addresses| addresses.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrap::WRAPPED_TOKEN;
+    use crate::{reset_state, test_utils, token_balance_of, OWNER};
+    use icp_token_wallet::account_text::decode_account;
+    use icp_token_wallet::icp_ledger::{ArchivedBlocksRange, TimeStamp, Tokens, Transaction};
+    use std::cell::Cell;
+    use test_utils::block_on;
+
+    const FEE: u128 = 10;
+
+    // In-memory ICP ledger whose blocks below `archived` live in an archive canister. An archive reply holds at
+    // most `archive_page` blocks, and `drop_archives` leaves the archived ranges out of `query_blocks`.
+    #[derive(Default)]
+    struct MockLedger {
+        blocks: Vec<Block>,
+        archived: u64,
+        archive_page: Option<usize>,
+        drop_archives: bool,
+        swept: RefCell<Vec<u128>>,
+        reject: Cell<bool>,
+    }
+
+    impl DepositLedger for MockLedger {
+        fn fee(&self) -> u128 {
+            FEE
+        }
+
+        async fn query_blocks(&self, start: u64, length: u64) -> Result<QueryBlocksResponse, TransferError> {
+            let chain_length = self.blocks.len() as u64;
+            let end = (start + length).min(chain_length);
+            let first_block_index = start.max(self.archived).min(end);
+            let mut archived_blocks = Vec::new();
+            if start < first_block_index && !self.drop_archives {
+                archived_blocks.push(ArchivedBlocksRange {
+                    start,
+                    length: first_block_index - start,
+                    callback: QueryArchiveFn::new(Principal::anonymous(), "get_blocks".to_string()),
+                });
+            }
+            Ok(QueryBlocksResponse {
+                chain_length,
+                certificate: None,
+                blocks: self.blocks[first_block_index as usize..end as usize].to_vec(),
+                first_block_index,
+                archived_blocks,
+            })
+        }
+
+        async fn query_archive(&self, callback: &QueryArchiveFn, start: u64, length: u64) -> Result<Vec<Block>, TransferError> {
+            assert_eq!(callback.0.method, "get_blocks");
+            let length = self.archive_page.map_or(length as usize, |page| page.min(length as usize));
+            Ok(self.blocks[start as usize..start as usize + length].to_vec())
+        }
+
+        async fn sweep(&self, _subaccount: [u8; 32], amount: u128) -> Result<u64, TransferError> {
+            if self.reject.get() {
+                return Err(TransferError::CanisterCallFailed("SysTransient: unavailable".to_string()));
+            }
+            self.swept.borrow_mut().push(amount);
+            Ok(self.swept.borrow().len() as u64 - 1)
+        }
+    }
+
+    fn block(operation: Operation) -> Block {
+        let transaction = Transaction {
+            memo: 0,
+            icrc1_memo: None,
+            operation: Some(operation),
+            created_at_time: TimeStamp { timestamp_nanos: 0 },
+        };
+        Block { parent_hash: None, transaction, timestamp: TimeStamp { timestamp_nanos: 0 } }
+    }
+
+    fn transfer_to(to: &Account, amount: u64) -> Block {
+        let subaccount: Option<[u8; 32]> = to.subaccount.as_ref().map(|subaccount| subaccount.as_slice().try_into().unwrap());
+        block(Operation::Transfer {
+            from: AccountIdentifier::new(&Principal::anonymous(), None).as_bytes().to_vec(),
+            to: AccountIdentifier::new(&to.owner, subaccount.as_ref()).as_bytes().to_vec(),
+            amount: Tokens { e8s: amount },
+            fee: Tokens { e8s: FEE as u64 },
+        })
+    }
+
+    // Deposits are credited as WICP.
+    fn balance_of(owner: &Principal) -> u128 {
+        token_balance_of(owner, WRAPPED_TOKEN)
+    }
+
+    fn credited_blocks(deposits: Vec<Deposit>) -> Vec<u64> {
+        deposits.iter().map(|deposit| deposit.source_block_index).collect()
+    }
+
+    #[test]
+    fn test_sweep_credits_each_block_once() {
+        reset_state();
+        let canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        register_deposit_account(canister, alice);
+        register_deposit_account(canister, bob);
+        let mint = block(Operation::Mint {
+            to: AccountIdentifier::new(&canister, Some(&principal_subaccount(&bob))).as_bytes().to_vec(),
+            amount: Tokens { e8s: 50 },
+        });
+        let ledger = MockLedger {
+            blocks: vec![
+                transfer_to(&deposit_account(canister, &alice), 100),
+                transfer_to(&Account::new(canister), 500),
+                transfer_to(&deposit_account(Principal::anonymous(), &alice), 100),
+                transfer_to(&deposit_account(canister, &alice), FEE as u64),
+                mint,
+                // Carol never registered her deposit address.
+                transfer_to(&deposit_account(canister, &carol), 100),
+            ],
+            ..Default::default()
+        };
+
+        ledger.reject.set(true);
+        let credited = block_on(sweep_deposits_with(&ledger, canister)).unwrap();
+        assert_eq!(credited_blocks(credited), vec![0, 3, 4]);
+        assert_eq!((balance_of(&alice), balance_of(&bob), balance_of(&carol)), (90, 40, 0));
+        assert_eq!(get_deposit(3).unwrap().status, DepositStatus::BelowFee);
+        assert_eq!(pending_sweeps(), 130);
+        assert_eq!(get_next_deposit_block(), 6);
+
+        // Rescanning from the start credits nothing twice, and the failed sweeps are retried.
+        ledger.reject.set(false);
+        NEXT_SOURCE_BLOCK.with(|next| *next.borrow_mut() = 0);
+        assert!(block_on(sweep_deposits_with(&ledger, canister)).unwrap().is_empty());
+        assert_eq!((balance_of(&alice), balance_of(&bob)), (90, 40));
+        assert_eq!(*ledger.swept.borrow(), vec![90, 40]);
+        assert_eq!(get_deposit(4).unwrap().status, DepositStatus::Swept { sweep_block_index: 1 });
+        assert_eq!(pending_sweeps(), 0);
+        assert_eq!(get_deposits(alice).len(), 2);
+        assert_eq!(decode_account(&deposit_account(canister, &alice).to_string()), Ok(deposit_account(canister, &alice)));
+
+        let _guard = SweepGuard::new().unwrap();
+        assert!(matches!(block_on(sweep_deposits_with(&ledger, canister)), Err(TransferError::SweepInProgress)));
+    }
+
+    #[test]
+    fn test_sweep_reads_archived_blocks() {
+        reset_state();
+        let canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_slice(&[1]);
+        register_deposit_account(canister, alice);
+        let blocks: Vec<Block> = (1..=5).map(|amount| transfer_to(&deposit_account(canister, &alice), 100 * amount)).collect();
+
+        // Blocks 0..3 are archived; the sweep reads them through the archive and the rest from the ledger.
+        let ledger = MockLedger { blocks: blocks.clone(), archived: 3, ..Default::default() };
+        assert_eq!(credited_blocks(block_on(sweep_deposits_with(&ledger, canister)).unwrap()), vec![0, 1, 2, 3, 4]);
+        assert_eq!(balance_of(&alice), 1500 - 5 * FEE);
+
+        // A short archive reply ends the sweep there, and the next sweep picks up the rest.
+        reset_state();
+        register_deposit_account(canister, alice);
+        let ledger = MockLedger { blocks: blocks.clone(), archived: 3, archive_page: Some(2), ..Default::default() };
+        assert_eq!(credited_blocks(block_on(sweep_deposits_with(&ledger, canister)).unwrap()), vec![0, 1]);
+        assert_eq!(credited_blocks(block_on(sweep_deposits_with(&ledger, canister)).unwrap()), vec![2, 3, 4]);
+
+        // Without the archived range the gap is reported and nothing is skipped.
+        reset_state();
+        register_deposit_account(canister, alice);
+        let ledger = MockLedger { blocks, archived: 3, drop_archives: true, ..Default::default() };
+        assert!(matches!(block_on(sweep_deposits_with(&ledger, canister)), Err(TransferError::CanisterCallFailed(_))));
+        assert_eq!(get_next_deposit_block(), 0);
+    }
+
+    #[test]
+    fn test_set_next_deposit_block() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_slice(&[1]);
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        register_deposit_account(owner, alice);
+        let ledger = MockLedger {
+            blocks: (1..=4).map(|amount| transfer_to(&deposit_account(owner, &alice), 100 * amount)).collect(),
+            ..Default::default()
+        };
+
+        test_utils::set_caller(alice);
+        assert!(matches!(set_next_deposit_block(2), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(owner);
+        {
+            let _guard = SweepGuard::new().unwrap();
+            assert!(matches!(set_next_deposit_block(2), Err(TransferError::SweepInProgress)));
+        }
+        set_next_deposit_block(2).unwrap();
+        assert_eq!(credited_blocks(block_on(sweep_deposits_with(&ledger, owner)).unwrap()), vec![2, 3]);
+        assert_eq!(get_next_deposit_block(), 4);
+    }
+}
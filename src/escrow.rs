@@ -0,0 +1,285 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{charge_fee, credit_balance, debit_balance, ensure_covers_fee, get_caller, get_time, record_event, schedule_at, Operation, TransferError};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum EscrowStatus {
+    Pending,
+    Released,
+    Refunded,
+    // Split by the arbiter; `payee_amount` went to the payee and the rest back to the payer.
+    Resolved { payee_amount: u128 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Escrow {
+    id: u64,
+    payer: Principal,
+    payee: Principal,
+    arbiter: Principal,
+    amount: u128,
+    created_at: u64,
+    // Pending escrows are refunded to the payer at this time.
+    deadline: u64,
+    status: EscrowStatus,
+}
+
+thread_local! {
+    static ESCROWS: RefCell<BTreeMap<u64, Escrow>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_ESCROW_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Total of the pending escrows `payer` funded.
+pub(crate) fn escrowed_by(payer: &Principal) -> u128 {
+    ESCROWS.with(|escrows| {
+        escrows.borrow()
+            .values()
+            .filter(|escrow| escrow.payer == *payer && escrow.status == EscrowStatus::Pending)
+            .map(|escrow| escrow.amount)
+            .sum()
+    })
+}
+
+// Marks a pending escrow as settled with `status` and returns it; the caller pays it out.
+fn settle(escrow_id: u64, status: EscrowStatus, authorized: impl FnOnce(&Escrow) -> bool) -> Result<Escrow, TransferError> {
+    ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+        let escrow = escrows.get_mut(&escrow_id).ok_or(TransferError::EscrowNotFound)?;
+        if escrow.status != EscrowStatus::Pending {
+            return Err(TransferError::EscrowNotPending);
+        }
+        if !authorized(escrow) {
+            return Err(TransferError::Unauthorized);
+        }
+        escrow.status = status;
+        Ok(escrow.clone())
+    })
+}
+
+fn pay_out(escrow: &Escrow, payee_amount: u128) -> Result<(), TransferError> {
+    let refund_amount = escrow.amount - payee_amount;
+    if payee_amount > 0 {
+        credit_balance(escrow.payee, payee_amount)?;
+        record_event(Operation::EscrowRelease, escrow.payer, escrow.payee, payee_amount);
+    }
+    if refund_amount > 0 {
+        credit_balance(escrow.payer, refund_amount)?;
+        record_event(Operation::EscrowRefund, escrow.payer, escrow.payer, refund_amount);
+    }
+    Ok(())
+}
+
+fn refund_expired(escrow_id: u64) {
+    let now = get_time();
+    if let Ok(escrow) = settle(escrow_id, EscrowStatus::Refunded, |escrow| now >= escrow.deadline) {
+        if let Err(err) = pay_out(&escrow, 0) {
+            ic_cdk::println!("Failed to refund expired escrow {}: {:?}", escrow_id, err);
+        }
+    }
+}
+
+#[update]
+fn create_escrow(payee: Principal, amount: u128, arbiter: Principal, deadline: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    let now = get_time();
+    if deadline <= now {
+        return Err(TransferError::InvalidDeadline);
+    }
+
+    ensure_covers_fee(&caller, amount)?;
+    debit_balance(caller, amount)?;
+    let id = NEXT_ESCROW_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    ESCROWS.with(|escrows| {
+        escrows.borrow_mut().insert(id, Escrow {
+            id,
+            payer: caller,
+            payee,
+            arbiter,
+            amount,
+            created_at: now,
+            deadline,
+            status: EscrowStatus::Pending,
+        });
+    });
+    schedule_at(deadline, move || refund_expired(id));
+
+    record_event(Operation::EscrowCreate, caller, payee, amount);
+    charge_fee(caller)?;
+    Ok(id)
+}
+
+// Pays the full amount to the payee. Called by the payer on delivery, or by the arbiter.
+#[update]
+fn release_escrow(escrow_id: u64) -> Result<(), TransferError> {
+    let caller = get_caller();
+    let escrow = settle(escrow_id, EscrowStatus::Released, |escrow| caller == escrow.payer || caller == escrow.arbiter)?;
+    pay_out(&escrow, escrow.amount)
+}
+
+// Returns the full amount to the payer. The payee and the arbiter can refund at any time, the payer only once
+// the deadline has passed.
+#[update]
+fn refund_escrow(escrow_id: u64) -> Result<(), TransferError> {
+    let caller = get_caller();
+    let now = get_time();
+    let escrow = settle(escrow_id, EscrowStatus::Refunded, |escrow| {
+        caller == escrow.payee || caller == escrow.arbiter || (caller == escrow.payer && now >= escrow.deadline)
+    })?;
+    pay_out(&escrow, 0)
+}
+
+#[update]
+fn dispute_resolve(escrow_id: u64, payee_amount: u128) -> Result<(), TransferError> {
+    let caller = get_caller();
+    if payee_amount > get_escrow(escrow_id).ok_or(TransferError::EscrowNotFound)?.amount {
+        return Err(TransferError::InvalidAmount);
+    }
+    let escrow = settle(escrow_id, EscrowStatus::Resolved { payee_amount }, |escrow| caller == escrow.arbiter)?;
+    pay_out(&escrow, payee_amount)
+}
+
+#[query]
+fn get_escrow(escrow_id: u64) -> Option<Escrow> {
+    ESCROWS.with(|escrows| escrows.borrow().get(&escrow_id).cloned())
+}
+
+// Escrows in which `account` is the payer, payee or arbiter.
+#[query]
+fn get_escrows(account: Principal) -> Vec<Escrow> {
+    ESCROWS.with(|escrows| {
+        escrows.borrow()
+            .values()
+            .filter(|escrow| [escrow.payer, escrow.payee, escrow.arbiter].contains(&account))
+            .cloned()
+            .collect()
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        ESCROWS.with(|escrows| escrows.take()),
+        NEXT_ESCROW_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (escrows, next_id) = candid::decode_one(bytes)?;
+    ESCROWS.with(|state| *state.borrow_mut() = escrows);
+    NEXT_ESCROW_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+// Timers do not survive an upgrade; re-arms the expiry refund of every pending escrow.
+pub(crate) fn resume_timers() {
+    let pending: Vec<(u64, u64)> = ESCROWS.with(|escrows| {
+        escrows
+            .borrow()
+            .values()
+            .filter(|escrow| escrow.status == EscrowStatus::Pending)
+            .map(|escrow| (escrow.id, escrow.deadline))
+            .collect()
+    });
+    for (id, deadline) in pending {
+        schedule_at(deadline, move || refund_expired(id));
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    ESCROWS.with(|escrows| escrows.borrow_mut().clear());
+    NEXT_ESCROW_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, get_balance, mint, reset_state, test_utils, OWNER, TOKEN};
+
+    fn setup() -> (Principal, Principal, Principal) {
+        reset_state();
+        let buyer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let seller = Principal::from_text("aaaaa-aa").unwrap();
+        let arbiter = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = buyer);
+        test_utils::set_caller(buyer);
+        assert!(mint(buyer, 1000).is_ok());
+        (buyer, seller, arbiter)
+    }
+
+    #[test]
+    fn test_release_and_refund() {
+        let (buyer, seller, arbiter) = setup();
+
+        assert!(matches!(create_escrow(seller, 300, arbiter, 0), Err(TransferError::InvalidDeadline)));
+        let first = create_escrow(seller, 300, arbiter, 100).unwrap();
+        let second = create_escrow(seller, 200, arbiter, 100).unwrap();
+        assert_eq!(get_balance(buyer).total, 500);
+        assert_eq!(get_balance(buyer).escrowed, 500);
+        TOKEN.with(|token| assert_eq!(token.borrow().total_supply, 1_000_000_000_000_001_000));
+
+        test_utils::set_caller(seller);
+        assert!(matches!(release_escrow(first), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(buyer);
+        assert!(matches!(refund_escrow(second), Err(TransferError::Unauthorized)));
+        assert!(release_escrow(first).is_ok());
+        assert!(matches!(release_escrow(first), Err(TransferError::EscrowNotPending)));
+
+        test_utils::set_caller(seller);
+        assert!(refund_escrow(second).is_ok());
+
+        assert_eq!(balance_of(&seller), 300);
+        assert_eq!(get_balance(buyer), crate::Balance { total: 700, locked: 0, available: 700, escrowed: 0 });
+        assert_eq!(get_escrows(arbiter).len(), 2);
+    }
+
+    #[test]
+    fn test_dispute_and_expiry() {
+        let (buyer, seller, arbiter) = setup();
+
+        let disputed = create_escrow(seller, 300, arbiter, 100).unwrap();
+        let expiring = create_escrow(seller, 200, arbiter, 100).unwrap();
+
+        test_utils::set_caller(buyer);
+        assert!(matches!(dispute_resolve(disputed, 100), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(arbiter);
+        assert!(matches!(dispute_resolve(disputed, 301), Err(TransferError::InvalidAmount)));
+        assert!(dispute_resolve(disputed, 100).is_ok());
+        assert_eq!(get_escrow(disputed).unwrap().status, EscrowStatus::Resolved { payee_amount: 100 });
+
+        // The timer fires at the deadline; firing early is a no-op.
+        test_utils::set_time(99);
+        refund_expired(expiring);
+        assert_eq!(get_escrow(expiring).unwrap().status, EscrowStatus::Pending);
+        test_utils::set_time(100);
+        refund_expired(expiring);
+        assert_eq!(get_escrow(expiring).unwrap().status, EscrowStatus::Refunded);
+
+        assert_eq!(balance_of(&seller), 100);
+        assert_eq!(balance_of(&buyer), 900);
+    }
+
+    #[test]
+    fn test_fee_charged_when_funded() {
+        let (buyer, seller, arbiter) = setup();
+        assert!(crate::set_transfer_fee(10).is_ok());
+
+        assert!(matches!(create_escrow(seller, 995, arbiter, 100), Err(TransferError::InsufficientBalance)));
+        let id = create_escrow(seller, 300, arbiter, 100).unwrap();
+        assert_eq!(balance_of(&buyer), 690);
+
+        // The payout itself is free.
+        assert!(release_escrow(id).is_ok());
+        assert_eq!((balance_of(&buyer), balance_of(&seller)), (690, 300));
+    }
+}
@@ -0,0 +1,338 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{get_caller, get_time, Operation, TransferError, TransferEvent, TRANSFER_EVENTS};
+
+// How often new events are pushed to subscribers.
+#[cfg(not(test))]
+const DELIVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_BATCH_EVENTS: usize = 100;
+// Bounds the work per subscriber and tick when few events match its filter.
+const MAX_SCANNED_EVENTS: usize = 1000;
+// Bounds the work per tick, together with `MAX_SCANNED_EVENTS`.
+const MAX_EVENT_SUBSCRIBERS: usize = 100;
+// Bounds the accounts a filter can list.
+const MAX_FILTER_ACCOUNTS: usize = 100;
+// A subscriber whose batches cannot be sent this many times in a row is dropped.
+const MAX_FAILED_DELIVERIES: u32 = 5;
+
+// Empty lists match everything. `accounts` matches either side of an event.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct EventFilter {
+    accounts: BTreeSet<Principal>,
+    operations: BTreeSet<Operation>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct EventSubscription {
+    filter: EventFilter,
+    // Block index from which the next batch is built.
+    cursor: u64,
+    last_delivery_at: Option<u64>,
+    // Sends that failed since the last successful one.
+    failed_deliveries: u32,
+}
+
+// Argument of the `on_events` method subscribers implement. The batch covers the block range
+// `[from_cursor, next_cursor)`; a subscriber that sees a `from_cursor` other than the `next_cursor` it last
+// received has missed a batch and can fill the gap with `get_filtered_events` or rewind with `set_event_cursor`.
+#[derive(CandidType, Deserialize, Clone)]
+struct EventBatch {
+    from_cursor: u64,
+    next_cursor: u64,
+    events: Vec<(u64, TransferEvent)>,
+}
+
+thread_local! {
+    static EVENT_SUBSCRIPTIONS: RefCell<BTreeMap<Principal, EventSubscription>> = const { RefCell::new(BTreeMap::new()) };
+    static DELIVERY_STARTED: RefCell<bool> = const { RefCell::new(false) };
+}
+
+impl EventFilter {
+    fn matches(&self, event: &TransferEvent) -> bool {
+        (self.accounts.is_empty() || self.accounts.contains(&event.from) || self.accounts.contains(&event.to))
+            && (self.operations.is_empty() || self.operations.contains(&event.operation))
+    }
+}
+
+// Opaque principals ending in 0x01 are canister ids; users and the anonymous principal cannot take one-way calls.
+fn is_canister(principal: &Principal) -> bool {
+    principal.as_slice().last() == Some(&0x01)
+}
+
+fn events_len() -> u64 {
+    TRANSFER_EVENTS.with(|events| events.borrow().len() as u64)
+}
+
+// Builds the batch starting at `cursor`; `None` when there is nothing new.
+fn next_batch(filter: &EventFilter, cursor: u64) -> Option<EventBatch> {
+    TRANSFER_EVENTS.with(|events| {
+        let events = events.borrow();
+        let start = cursor as usize;
+        if start >= events.len() {
+            return None;
+        }
+        let mut batch = Vec::new();
+        let mut next = start;
+        for event in events[start..].iter().take(MAX_SCANNED_EVENTS) {
+            if batch.len() == MAX_BATCH_EVENTS {
+                break;
+            }
+            if filter.matches(event) {
+                batch.push((next as u64, event.clone()));
+            }
+            next += 1;
+        }
+        Some(EventBatch { from_cursor: cursor, next_cursor: next as u64, events: batch })
+    })
+}
+
+// Sends each subscriber its next batch as a one-way call. A cursor only moves once the call has been enqueued;
+// whether the subscriber processed the batch is not known, which is what the batch cursors are for. Subscribers
+// whose sends keep failing are dropped.
+fn deliver_batches(send: impl Fn(Principal, &EventBatch) -> bool) {
+    let now = get_time();
+    let subscriptions: Vec<(Principal, EventSubscription)> =
+        EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().iter().map(|(id, sub)| (*id, sub.clone())).collect());
+
+    for (subscriber, subscription) in subscriptions {
+        let Some(batch) = next_batch(&subscription.filter, subscription.cursor) else {
+            continue;
+        };
+        // Ranges without a matching event only move the cursor.
+        if !batch.events.is_empty() && !send(subscriber, &batch) {
+            EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+                let mut subscriptions = subscriptions.borrow_mut();
+                if let Some(current) = subscriptions.get_mut(&subscriber) {
+                    current.failed_deliveries += 1;
+                    if current.failed_deliveries >= MAX_FAILED_DELIVERIES {
+                        subscriptions.remove(&subscriber);
+                        ic_cdk::println!("Dropped event subscriber {} after {} failed deliveries", subscriber, MAX_FAILED_DELIVERIES);
+                    }
+                }
+            });
+            continue;
+        }
+        EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+            if let Some(current) = subscriptions.borrow_mut().get_mut(&subscriber) {
+                current.failed_deliveries = 0;
+                // The subscriber may have moved its cursor while the batch was being built.
+                if current.cursor == batch.from_cursor {
+                    current.cursor = batch.next_cursor;
+                    if !batch.events.is_empty() {
+                        current.last_delivery_at = Some(now);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(test))]
+fn start_delivery() {
+    if DELIVERY_STARTED.with(|started| started.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, || {
+        deliver_batches(|subscriber, batch| {
+            match ic_cdk::notify(subscriber, "on_events", (batch.clone(),)) {
+                Ok(()) => true,
+                Err(code) => {
+                    ic_cdk::println!("Failed to push events to {}: {:?}", subscriber, code);
+                    false
+                }
+            }
+        })
+    });
+}
+
+#[cfg(test)]
+fn start_delivery() {
+    DELIVERY_STARTED.with(|started| *started.borrow_mut() = true);
+}
+
+// Subscribes the calling canister to events matching `filter`, starting with the next event. Subscribing again
+// replaces the filter and keeps the cursor.
+#[update]
+fn subscribe_events(filter: EventFilter) -> Result<(), TransferError> {
+    let caller = get_caller();
+    if !is_canister(&caller) {
+        return Err(TransferError::Unauthorized);
+    }
+    if filter.accounts.len() > MAX_FILTER_ACCOUNTS {
+        return Err(TransferError::EventFilterTooLarge);
+    }
+    let cursor = events_len();
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        if let Some(subscription) = subscriptions.get_mut(&caller) {
+            subscription.filter = filter;
+            return Ok(());
+        }
+        if subscriptions.len() >= MAX_EVENT_SUBSCRIBERS {
+            return Err(TransferError::TooManyEventSubscribers);
+        }
+        subscriptions.insert(caller, EventSubscription { filter, cursor, last_delivery_at: None, failed_deliveries: 0 });
+        Ok(())
+    })?;
+    start_delivery();
+    Ok(())
+}
+
+#[update]
+fn unsubscribe_events() -> Result<(), TransferError> {
+    let caller = get_caller();
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().remove(&caller))
+        .map(|_| ())
+        .ok_or(TransferError::EventSubscriptionNotFound)
+}
+
+// Moves the caller's cursor, typically back to the last block index it processed after an outage.
+#[update]
+fn set_event_cursor(cursor: u64) -> Result<(), TransferError> {
+    let caller = get_caller();
+    if cursor > events_len() {
+        return Err(TransferError::InvalidCursor);
+    }
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        let subscription = subscriptions.get_mut(&caller).ok_or(TransferError::EventSubscriptionNotFound)?;
+        subscription.cursor = cursor;
+        Ok(())
+    })
+}
+
+#[query]
+fn get_event_subscription(subscriber: Principal) -> Option<EventSubscription> {
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&subscriber).cloned())
+}
+
+// The batch the caller would be pushed from `cursor` with its current filter, for pulling missed events.
+#[query]
+fn get_filtered_events(cursor: u64) -> Result<Option<EventBatch>, TransferError> {
+    let filter = get_event_subscription(get_caller()).ok_or(TransferError::EventSubscriptionNotFound)?.filter;
+    Ok(next_batch(&filter, cursor))
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let subscriptions = candid::decode_one(bytes)?;
+    EVENT_SUBSCRIPTIONS.with(|state| *state.borrow_mut() = subscriptions);
+    Ok(())
+}
+
+// Restarts delivery after an upgrade if any canister is still subscribed.
+pub(crate) fn resume_timers() {
+    if EVENT_SUBSCRIPTIONS.with(|subscriptions| !subscriptions.borrow().is_empty()) {
+        start_delivery();
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    EVENT_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().clear());
+    DELIVERY_STARTED.with(|started| *started.borrow_mut() = false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mint, reset_state, test_utils, transfer, OWNER};
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_push_filtered_batches() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let exchange = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let other = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 1000).is_ok());
+
+        test_utils::set_caller(other);
+        assert!(matches!(subscribe_events(EventFilter::default()), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(exchange);
+        subscribe_events(EventFilter {
+            accounts: BTreeSet::from([exchange]),
+            operations: BTreeSet::from([Operation::Transfer]),
+        }).unwrap();
+        assert_eq!(get_event_subscription(exchange).unwrap().cursor, 1);
+
+        test_utils::set_caller(owner);
+        assert!(transfer(exchange, 100).is_ok());
+        assert!(transfer(other, 100).is_ok());
+        assert!(transfer(exchange, 50).is_ok());
+
+        let sent = RefCell::new(Vec::new());
+        let record = |subscriber: Principal, batch: &EventBatch| {
+            sent.borrow_mut().push((subscriber, batch.from_cursor, batch.events.iter().map(|(index, _)| *index).collect::<Vec<_>>()));
+            true
+        };
+        deliver_batches(record);
+        assert_eq!(sent.take(), vec![(exchange, 1, vec![1, 3])]);
+        deliver_batches(record);
+        assert!(sent.take().is_empty());
+
+        // A failed send leaves the cursor where it was.
+        assert!(transfer(exchange, 10).is_ok());
+        deliver_batches(|_, _| false);
+        assert_eq!(get_event_subscription(exchange).unwrap().cursor, 4);
+
+        // After an outage the subscriber rewinds and receives everything again.
+        test_utils::set_caller(exchange);
+        assert!(matches!(set_event_cursor(99), Err(TransferError::InvalidCursor)));
+        assert!(set_event_cursor(1).is_ok());
+        deliver_batches(record);
+        assert_eq!(sent.take(), vec![(exchange, 1, vec![1, 3, 4])]);
+
+        assert!(unsubscribe_events().is_ok());
+        assert!(matches!(unsubscribe_events(), Err(TransferError::EventSubscriptionNotFound)));
+    }
+
+    #[test]
+    fn test_subscriber_limits() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 1000).is_ok());
+
+        for i in 0..MAX_EVENT_SUBSCRIBERS as u8 {
+            test_utils::set_caller(Principal::from_slice(&[i, 1]));
+            subscribe_events(EventFilter::default()).unwrap();
+        }
+        test_utils::set_caller(Principal::from_slice(&[255, 1]));
+        assert!(matches!(subscribe_events(EventFilter::default()), Err(TransferError::TooManyEventSubscribers)));
+        // An existing subscriber can still change its filter.
+        test_utils::set_caller(Principal::from_slice(&[0, 1]));
+        subscribe_events(EventFilter { accounts: BTreeSet::from([owner]), operations: BTreeSet::new() }).unwrap();
+        let accounts = (0..=MAX_FILTER_ACCOUNTS as u8).map(|i| Principal::from_slice(&[i])).collect();
+        assert!(matches!(
+            subscribe_events(EventFilter { accounts, operations: BTreeSet::new() }),
+            Err(TransferError::EventFilterTooLarge)
+        ));
+
+        // Subscriber 0 keeps failing and is dropped; a success in between resets the count of subscriber 1.
+        test_utils::set_caller(owner);
+        let failing = Principal::from_slice(&[0, 1]);
+        let flaky = Principal::from_slice(&[1, 1]);
+        for round in 0..MAX_FAILED_DELIVERIES {
+            assert!(transfer(Principal::anonymous(), 1).is_ok());
+            assert!(get_event_subscription(failing).is_some());
+            deliver_batches(|subscriber, _| subscriber != failing && (subscriber != flaky || round == 2));
+        }
+        assert!(get_event_subscription(failing).is_none());
+        assert_eq!(get_event_subscription(flaky).unwrap().failed_deliveries, MAX_FAILED_DELIVERIES - 3);
+        assert_eq!(get_event_subscription(Principal::from_slice(&[2, 1])).unwrap().failed_deliveries, 0);
+
+        // The freed slot can be taken.
+        test_utils::set_caller(Principal::from_slice(&[255, 1]));
+        subscribe_events(EventFilter::default()).unwrap();
+    }
+}
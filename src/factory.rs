@@ -0,0 +1,422 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+
+use crate::{get_caller, get_time, is_owner, InitArgs, TransferError};
+
+// Cycles attached to `create_canister` for every new ledger.
+const CYCLES_PER_LEDGER: u128 = 2_000_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct CreatedCanister {
+    canister_id: Principal,
+    controllers: Vec<Principal>,
+    init_args: InitArgs,
+    created_at: u64,
+    // Version of the uploaded wasm the canister runs, `None` until its install succeeds.
+    wasm_version: Option<u64>,
+}
+
+thread_local! {
+    static LEDGER_WASM: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static WASM_VERSION: RefCell<u64> = const { RefCell::new(0) };
+    static CREATED_CANISTERS: RefCell<Vec<CreatedCanister>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn call_failed((code, message): (RejectionCode, String)) -> TransferError {
+    TransferError::CanisterCallFailed(format!("{:?}: {}", code, message))
+}
+
+fn ledger_wasm() -> Result<(Vec<u8>, u64), TransferError> {
+    let wasm = LEDGER_WASM.with(|wasm| wasm.borrow().clone());
+    if wasm.is_empty() {
+        return Err(TransferError::LedgerWasmNotSet);
+    }
+    Ok((wasm, WASM_VERSION.with(|version| *version.borrow())))
+}
+
+// The management canister calls the factory needs, behind a trait so the tests can run without a replica.
+trait Management {
+    async fn create(&self, controllers: Vec<Principal>) -> Result<Principal, TransferError>;
+    async fn install(
+        &self,
+        mode: CanisterInstallMode,
+        canister_id: Principal,
+        wasm: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), TransferError>;
+}
+
+struct ManagementCanister;
+
+impl Management for ManagementCanister {
+    async fn create(&self, controllers: Vec<Principal>) -> Result<Principal, TransferError> {
+        let (record,) = create_canister(
+            CreateCanisterArgument {
+                settings: Some(CanisterSettings {
+                    controllers: Some(controllers),
+                    ..Default::default()
+                }),
+            },
+            CYCLES_PER_LEDGER,
+        )
+        .await
+        .map_err(call_failed)?;
+        Ok(record.canister_id)
+    }
+
+    async fn install(
+        &self,
+        mode: CanisterInstallMode,
+        canister_id: Principal,
+        wasm: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), TransferError> {
+        install_code(InstallCodeArgument {
+            mode,
+            canister_id,
+            wasm_module: wasm,
+            arg,
+        })
+        .await
+        .map_err(call_failed)
+    }
+}
+
+// A canister cannot read its own module, so the owner uploads the ledger wasm the factory installs.
+#[update]
+fn set_ledger_wasm(wasm: Vec<u8>) -> Result<u64, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    if wasm.is_empty() {
+        return Err(TransferError::LedgerWasmNotSet);
+    }
+
+    LEDGER_WASM.with(|current| *current.borrow_mut() = wasm);
+    let version = WASM_VERSION.with(|version| {
+        let mut version = version.borrow_mut();
+        *version += 1;
+        *version
+    });
+    ic_cdk::println!("Ledger wasm version {} uploaded", version);
+    Ok(version)
+}
+
+// Installs the ledger wasm with the canister's recorded init args and marks it installed on success.
+async fn install_with(management: &impl Management, canister_id: Principal) -> Result<(), TransferError> {
+    let (wasm, wasm_version) = ledger_wasm()?;
+    let init_args = CREATED_CANISTERS
+        .with(|created| {
+            created
+                .borrow()
+                .iter()
+                .find(|canister| canister.canister_id == canister_id && canister.wasm_version.is_none())
+                .map(|canister| canister.init_args.clone())
+        })
+        .ok_or(TransferError::TokenCanisterNotFound)?;
+    let arg = candid::encode_one(Some(init_args)).map_err(|err| TransferError::CanisterCallFailed(err.to_string()))?;
+
+    management.install(CanisterInstallMode::Install, canister_id, wasm, arg).await?;
+    set_wasm_version(canister_id, wasm_version);
+    Ok(())
+}
+
+fn set_wasm_version(canister_id: Principal, wasm_version: u64) {
+    CREATED_CANISTERS.with(|created| {
+        if let Some(canister) = created.borrow_mut().iter_mut().find(|canister| canister.canister_id == canister_id) {
+            canister.wasm_version = Some(wasm_version);
+        }
+    });
+}
+
+// Upgrades every installed canister that runs an older wasm to the latest upload, one at a time. A failed upgrade
+// leaves that canister on its old version, and calling this again retries it.
+async fn upgrade_with(management: &impl Management) -> Result<Vec<(Principal, Result<(), TransferError>)>, TransferError> {
+    let (wasm, wasm_version) = ledger_wasm()?;
+    // The ledger's `post_upgrade` takes no arguments; its state comes from stable memory.
+    let arg = candid::encode_args(()).map_err(|err| TransferError::CanisterCallFailed(err.to_string()))?;
+    let outdated: Vec<Principal> = CREATED_CANISTERS.with(|created| {
+        created.borrow()
+            .iter()
+            .filter(|canister| canister.wasm_version.is_some_and(|version| version < wasm_version))
+            .map(|canister| canister.canister_id)
+            .collect()
+    });
+
+    let mut results = Vec::new();
+    for canister_id in outdated {
+        let result = management.install(CanisterInstallMode::Upgrade(None), canister_id, wasm.clone(), arg.clone()).await;
+        if result.is_ok() {
+            set_wasm_version(canister_id, wasm_version);
+        }
+        results.push((canister_id, result));
+    }
+    Ok(results)
+}
+
+async fn create_token_canister_with(
+    management: &impl Management,
+    factory: Principal,
+    caller: Principal,
+    init_args: InitArgs,
+) -> Result<Principal, TransferError> {
+    ledger_wasm()?;
+    let controllers = vec![factory, caller];
+    let canister_id = management.create(controllers.clone()).await?;
+
+    // Recorded before the install so a failed install leaves a known canister the owner can retry.
+    CREATED_CANISTERS.with(|created| {
+        created.borrow_mut().push(CreatedCanister {
+            canister_id,
+            controllers,
+            init_args,
+            created_at: get_time(),
+            wasm_version: None,
+        });
+    });
+    install_with(management, canister_id).await?;
+
+    ic_cdk::println!("Created token canister {}", canister_id);
+    Ok(canister_id)
+}
+
+#[update]
+async fn create_token_canister(init_args: InitArgs) -> Result<Principal, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    create_token_canister_with(&ManagementCanister, ic_cdk::id(), get_caller(), init_args).await
+}
+
+// Retries the install on a created canister whose first install failed.
+#[update]
+async fn install_token_canister(canister_id: Principal) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    install_with(&ManagementCanister, canister_id).await
+}
+
+// Upgrades the created canisters to the latest uploaded wasm. Returns the outcome per canister.
+#[update]
+async fn upgrade_token_canisters() -> Result<Vec<(Principal, Result<(), TransferError>)>, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    upgrade_with(&ManagementCanister).await
+}
+
+#[query]
+fn get_created_canisters() -> Vec<CreatedCanister> {
+    CREATED_CANISTERS.with(|created| created.borrow().clone())
+}
+
+#[query]
+fn get_ledger_wasm_version() -> u64 {
+    WASM_VERSION.with(|version| *version.borrow())
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        LEDGER_WASM.with(|wasm| wasm.take()),
+        WASM_VERSION.with(|version| version.take()),
+        CREATED_CANISTERS.with(|canisters| canisters.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (wasm, version, canisters) = candid::decode_one(bytes)?;
+    LEDGER_WASM.with(|state| *state.borrow_mut() = wasm);
+    WASM_VERSION.with(|state| *state.borrow_mut() = version);
+    CREATED_CANISTERS.with(|state| *state.borrow_mut() = canisters);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    LEDGER_WASM.with(|wasm| wasm.borrow_mut().clear());
+    WASM_VERSION.with(|version| *version.borrow_mut() = 0);
+    CREATED_CANISTERS.with(|created| created.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reset_state, test_utils, OWNER};
+    use std::cell::Cell;
+    use test_utils::block_on;
+
+    #[test]
+    fn test_set_ledger_wasm() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        assert!(matches!(ledger_wasm(), Err(TransferError::LedgerWasmNotSet)));
+        test_utils::set_caller(Principal::anonymous());
+        assert!(matches!(set_ledger_wasm(vec![0, 97, 115, 109]), Err(TransferError::Unauthorized)));
+
+        test_utils::set_caller(owner);
+        assert!(matches!(set_ledger_wasm(Vec::new()), Err(TransferError::LedgerWasmNotSet)));
+        assert_eq!(set_ledger_wasm(vec![0, 97, 115, 109]).unwrap(), 1);
+        assert_eq!(set_ledger_wasm(vec![0, 97, 115, 109, 1]).unwrap(), 2);
+        assert_eq!(ledger_wasm().unwrap(), (vec![0, 97, 115, 109, 1], 2));
+    }
+
+    // Mode, canister id, wasm and arg of one install.
+    type Install = (CanisterInstallMode, Principal, Vec<u8>, Vec<u8>);
+
+    // Management canister that hands out sequential canister ids and fails installs while `reject` is set.
+    #[derive(Default)]
+    struct MockManagement {
+        next_id: Cell<u8>,
+        installed: RefCell<Vec<Install>>,
+        reject: Cell<bool>,
+    }
+
+    impl Management for MockManagement {
+        async fn create(&self, _controllers: Vec<Principal>) -> Result<Principal, TransferError> {
+            self.next_id.set(self.next_id.get() + 1);
+            Ok(Principal::from_slice(&[self.next_id.get()]))
+        }
+
+        async fn install(
+            &self,
+            mode: CanisterInstallMode,
+            canister_id: Principal,
+            wasm: Vec<u8>,
+            arg: Vec<u8>,
+        ) -> Result<(), TransferError> {
+            if self.reject.get() {
+                return Err(TransferError::CanisterCallFailed("CanisterError: install failed".to_string()));
+            }
+            self.installed.borrow_mut().push((mode, canister_id, wasm, arg));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_create_token_canister() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let factory = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        let management = MockManagement::default();
+        let init_args = InitArgs {
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            decimals: 8,
+            initial_supply: 1000,
+            owner: Some(owner),
+        };
+
+        assert!(matches!(
+            block_on(create_token_canister_with(&management, factory, owner, init_args.clone())),
+            Err(TransferError::LedgerWasmNotSet)
+        ));
+        assert!(get_created_canisters().is_empty());
+
+        test_utils::set_caller(owner);
+        set_ledger_wasm(vec![0, 97, 115, 109]).unwrap();
+        let canister_id = block_on(create_token_canister_with(&management, factory, owner, init_args.clone())).unwrap();
+        let created = get_created_canisters();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].canister_id, canister_id);
+        assert_eq!(created[0].controllers, vec![factory, owner]);
+        assert_eq!(created[0].wasm_version, Some(1));
+
+        // The install receives the wasm and the encoded init args.
+        let (mode, installed_id, wasm, arg) = management.installed.borrow()[0].clone();
+        assert_eq!((mode, installed_id), (CanisterInstallMode::Install, canister_id));
+        assert_eq!(wasm, vec![0, 97, 115, 109]);
+        assert_eq!(candid::decode_one::<Option<InitArgs>>(&arg).unwrap(), Some(init_args));
+    }
+
+    #[test]
+    fn test_failed_install_keeps_the_canister() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let factory = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        set_ledger_wasm(vec![0, 97, 115, 109]).unwrap();
+        let management = MockManagement::default();
+        let init_args = InitArgs {
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            decimals: 8,
+            initial_supply: 0,
+            owner: None,
+        };
+
+        management.reject.set(true);
+        assert!(matches!(
+            block_on(create_token_canister_with(&management, factory, owner, init_args)),
+            Err(TransferError::CanisterCallFailed(_))
+        ));
+        let canister_id = Principal::from_slice(&[1]);
+        let created = get_created_canisters();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].canister_id, canister_id);
+        assert_eq!(created[0].wasm_version, None);
+
+        // The retry installs the latest wasm into the recorded canister without creating another one.
+        management.reject.set(false);
+        set_ledger_wasm(vec![0, 97, 115, 109, 1]).unwrap();
+        assert!(matches!(
+            block_on(install_with(&management, Principal::from_slice(&[9]))),
+            Err(TransferError::TokenCanisterNotFound)
+        ));
+        block_on(install_with(&management, canister_id)).unwrap();
+        assert_eq!(get_created_canisters()[0].wasm_version, Some(2));
+        assert_eq!(management.next_id.get(), 1);
+
+        // An installed canister is not installed again.
+        assert!(matches!(block_on(install_with(&management, canister_id)), Err(TransferError::TokenCanisterNotFound)));
+    }
+
+    #[test]
+    fn test_upgrade_token_canisters() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let factory = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        set_ledger_wasm(vec![0, 97, 115, 109]).unwrap();
+        let management = MockManagement::default();
+        let init_args = InitArgs {
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            decimals: 8,
+            initial_supply: 0,
+            owner: None,
+        };
+        let first = block_on(create_token_canister_with(&management, factory, owner, init_args.clone())).unwrap();
+        let second = block_on(create_token_canister_with(&management, factory, owner, init_args.clone())).unwrap();
+        management.reject.set(true);
+        let uninstalled = block_on(create_token_canister_with(&management, factory, owner, init_args)).unwrap_err();
+        assert!(matches!(uninstalled, TransferError::CanisterCallFailed(_)));
+        management.reject.set(false);
+
+        // Nothing is outdated until a new wasm is uploaded.
+        assert!(block_on(upgrade_with(&management)).unwrap().is_empty());
+        set_ledger_wasm(vec![0, 97, 115, 109, 2]).unwrap();
+        let results = block_on(upgrade_with(&management)).unwrap();
+        assert_eq!(results.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![first, second]);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        let installed = management.installed.borrow();
+        let (mode, canister_id, wasm, arg) = installed.last().unwrap().clone();
+        assert_eq!((mode, canister_id, wasm), (CanisterInstallMode::Upgrade(None), second, vec![0, 97, 115, 109, 2]));
+        assert!(candid::decode_args::<()>(&arg).is_ok());
+        let versions: Vec<Option<u64>> = get_created_canisters().iter().map(|canister| canister.wasm_version).collect();
+        assert_eq!(versions, vec![Some(2), Some(2), None]);
+        drop(installed);
+        assert!(block_on(upgrade_with(&management)).unwrap().is_empty());
+    }
+}
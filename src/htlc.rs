@@ -0,0 +1,202 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{charge_fee, credit_balance, debit_balance, ensure_covers_fee, get_caller, get_time, record_event, Operation, TransferError};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum HtlcStatus {
+    Locked,
+    // The preimage is kept so the counterparty of a swap can use it on the other ledger.
+    Claimed { preimage: Vec<u8> },
+    Refunded,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct HashedLock {
+    id: u64,
+    from: Principal,
+    to: Principal,
+    amount: u128,
+    // SHA-256 of the preimage that releases the funds to `to`.
+    hashlock: Vec<u8>,
+    // Claims are accepted strictly before this time; from then on the funds can only go back to `from`.
+    timeout: u64,
+    status: HtlcStatus,
+}
+
+thread_local! {
+    static HASHED_LOCKS: RefCell<BTreeMap<u64, HashedLock>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_HTLC_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Applies a state change to a locked HTLC and returns the updated lock.
+fn transition(
+    id: u64,
+    f: impl FnOnce(&HashedLock) -> Result<HtlcStatus, TransferError>,
+) -> Result<HashedLock, TransferError> {
+    HASHED_LOCKS.with(|locks| {
+        let mut locks = locks.borrow_mut();
+        let lock = locks.get_mut(&id).ok_or(TransferError::HtlcNotFound)?;
+        if lock.status != HtlcStatus::Locked {
+            return Err(TransferError::HtlcNotLocked);
+        }
+        lock.status = f(lock)?;
+        Ok(lock.clone())
+    })
+}
+
+#[update]
+fn lock_hashed(to: Principal, amount: u128, hashlock: Vec<u8>, timeout: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    if hashlock.len() != 32 {
+        return Err(TransferError::InvalidHashlock);
+    }
+    if timeout <= get_time() {
+        return Err(TransferError::InvalidDeadline);
+    }
+
+    ensure_covers_fee(&caller, amount)?;
+    debit_balance(caller, amount)?;
+    let id = NEXT_HTLC_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    HASHED_LOCKS.with(|locks| {
+        locks.borrow_mut().insert(id, HashedLock {
+            id,
+            from: caller,
+            to,
+            amount,
+            hashlock,
+            timeout,
+            status: HtlcStatus::Locked,
+        });
+    });
+
+    record_event(Operation::HtlcLock, caller, to, amount);
+    charge_fee(caller)?;
+    Ok(id)
+}
+
+// Anyone holding the preimage can claim; the funds always go to the recipient.
+#[update]
+fn claim_with_preimage(id: u64, preimage: Vec<u8>) -> Result<(), TransferError> {
+    let now = get_time();
+    let lock = transition(id, |lock| {
+        if now >= lock.timeout {
+            return Err(TransferError::HtlcExpired);
+        }
+        if Sha256::digest(&preimage).as_slice() != lock.hashlock.as_slice() {
+            return Err(TransferError::InvalidPreimage);
+        }
+        Ok(HtlcStatus::Claimed { preimage })
+    })?;
+
+    credit_balance(lock.to, lock.amount)?;
+    record_event(Operation::HtlcClaim, lock.from, lock.to, lock.amount);
+    Ok(())
+}
+
+#[update]
+fn refund_after_timeout(id: u64) -> Result<(), TransferError> {
+    let now = get_time();
+    let lock = transition(id, |lock| {
+        if now < lock.timeout {
+            return Err(TransferError::HtlcNotExpired);
+        }
+        Ok(HtlcStatus::Refunded)
+    })?;
+
+    credit_balance(lock.from, lock.amount)?;
+    record_event(Operation::HtlcRefund, lock.from, lock.from, lock.amount);
+    Ok(())
+}
+
+#[query]
+fn get_hashed_lock(id: u64) -> Option<HashedLock> {
+    HASHED_LOCKS.with(|locks| locks.borrow().get(&id).cloned())
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        HASHED_LOCKS.with(|locks| locks.take()),
+        NEXT_HTLC_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (locks, next_id) = candid::decode_one(bytes)?;
+    HASHED_LOCKS.with(|state| *state.borrow_mut() = locks);
+    NEXT_HTLC_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    HASHED_LOCKS.with(|locks| locks.borrow_mut().clear());
+    NEXT_HTLC_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, mint, reset_state, test_utils, OWNER};
+
+    fn setup() -> (Principal, Principal) {
+        reset_state();
+        let alice = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let bob = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = alice);
+        test_utils::set_caller(alice);
+        assert!(mint(alice, 1000).is_ok());
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_claim_with_preimage() {
+        let (alice, bob) = setup();
+        let preimage = b"swap secret".to_vec();
+        let hashlock = Sha256::digest(&preimage).to_vec();
+
+        test_utils::set_time(10);
+        assert!(matches!(lock_hashed(bob, 300, vec![0; 31], 100), Err(TransferError::InvalidHashlock)));
+        assert!(matches!(lock_hashed(bob, 300, hashlock.clone(), 10), Err(TransferError::InvalidDeadline)));
+        let id = lock_hashed(bob, 300, hashlock, 100).unwrap();
+        assert_eq!(balance_of(&alice), 700);
+
+        test_utils::set_caller(bob);
+        assert!(matches!(claim_with_preimage(id, b"wrong".to_vec()), Err(TransferError::InvalidPreimage)));
+        assert!(matches!(refund_after_timeout(id), Err(TransferError::HtlcNotExpired)));
+        test_utils::set_time(99);
+        assert!(claim_with_preimage(id, preimage.clone()).is_ok());
+        assert!(matches!(claim_with_preimage(id, preimage.clone()), Err(TransferError::HtlcNotLocked)));
+
+        assert_eq!(balance_of(&bob), 300);
+        assert_eq!(get_hashed_lock(id).unwrap().status, HtlcStatus::Claimed { preimage });
+    }
+
+    #[test]
+    fn test_refund_after_timeout() {
+        let (alice, bob) = setup();
+        let preimage = b"swap secret".to_vec();
+        let id = lock_hashed(bob, 300, Sha256::digest(&preimage).to_vec(), 100).unwrap();
+
+        test_utils::set_time(100);
+        test_utils::set_caller(bob);
+        assert!(matches!(claim_with_preimage(id, preimage), Err(TransferError::HtlcExpired)));
+        assert!(refund_after_timeout(id).is_ok());
+        assert!(matches!(refund_after_timeout(id), Err(TransferError::HtlcNotLocked)));
+
+        assert_eq!(balance_of(&alice), 1000);
+        assert_eq!(balance_of(&bob), 0);
+        assert_eq!(get_hashed_lock(id).unwrap().status, HtlcStatus::Refunded);
+    }
+}
@@ -0,0 +1,205 @@
+// Candid types of the ICRC-1 and ICRC-2 methods of the ICP ledger that the wrapping module calls, and of the
+// `query_blocks` method deposit detection reads, shared with the stand-in ledger in `src/bin/icp_ledger_stub.rs`.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn new(owner: Principal) -> Account {
+        Account { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct GetBlocksArgs {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+// Accounts in blocks are 32-byte legacy account identifiers, see `account_identifier`. Candid skips the record
+// fields these variants leave out.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Operation {
+    Mint { to: Vec<u8>, amount: Tokens },
+    Burn { from: Vec<u8>, amount: Tokens },
+    Transfer { from: Vec<u8>, to: Vec<u8>, amount: Tokens, fee: Tokens },
+    Approve { from: Vec<u8>, spender: Vec<u8> },
+}
+
+// An operation of a kind added to the ledger later decodes as `None`, since it sits in an `opt`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<Operation>,
+    pub created_at_time: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Block {
+    pub parent_hash: Option<Vec<u8>>,
+    pub transaction: Transaction,
+    pub timestamp: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockRange {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum QueryArchiveError {
+    BadFirstBlockIndex { requested_index: u64, first_valid_index: u64 },
+    Other { error_code: u64, error_message: String },
+}
+
+pub type QueryArchiveResult = Result<BlockRange, QueryArchiveError>;
+
+candid::define_function!(pub QueryArchiveFn : (GetBlocksArgs) -> (QueryArchiveResult) query);
+
+// Blocks in `[start, start + length)` that moved to an archive canister, fetched by calling `callback` with a
+// `GetBlocksArgs` inside the range.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocksRange {
+    pub start: u64,
+    pub length: u64,
+    pub callback: QueryArchiveFn,
+}
+
+// Reply of `query_blocks`: the requested blocks the ledger still holds, starting at `first_block_index`, and the
+// archived ranges that precede them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueryBlocksResponse {
+    pub chain_length: u64,
+    pub certificate: Option<Vec<u8>>,
+    pub blocks: Vec<Block>,
+    pub first_block_index: u64,
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+}
+
+// Subaccount holding `principal`'s deposits: its length followed by its bytes, zero-padded to 32 bytes.
+pub fn principal_subaccount(principal: &Principal) -> [u8; 32] {
+    let bytes = principal.as_slice();
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+// Inverse of `principal_subaccount`; `None` for subaccounts it does not produce. The all-zero default
+// subaccount is not mapped to the management canister.
+pub fn subaccount_principal(subaccount: &[u8]) -> Option<Principal> {
+    let length = *subaccount.first()? as usize;
+    if subaccount.len() != 32 || length == 0 || length > Principal::MAX_LENGTH_IN_BYTES {
+        return None;
+    }
+    let (bytes, padding) = subaccount[1..].split_at(length);
+    if padding.iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    Some(Principal::from_slice(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_principal_subaccount_roundtrip() {
+        for principal in [Principal::anonymous(), Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()] {
+            assert_eq!(subaccount_principal(&principal_subaccount(&principal)), Some(principal));
+        }
+        let mut subaccount = principal_subaccount(&Principal::anonymous());
+        subaccount[31] = 1;
+        assert_eq!(subaccount_principal(&subaccount), None);
+        assert_eq!(subaccount_principal(&[30; 32]), None);
+        assert_eq!(subaccount_principal(&[0; 32]), None);
+        assert_eq!(subaccount_principal(&[0; 31]), None);
+    }
+}
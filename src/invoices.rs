@@ -0,0 +1,193 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{get_caller, get_time, transfer_from, TransferError};
+
+const MAX_MEMO_LENGTH: usize = 256;
+const MAX_INVOICES_PAGE: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum InvoiceStatus {
+    Open,
+    Paid { payer: Principal, block_index: u64, paid_at: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Invoice {
+    id: u64,
+    merchant: Principal,
+    amount: u128,
+    memo: String,
+    created_at: u64,
+    // Payments are accepted strictly before this time.
+    expiry: u64,
+    status: InvoiceStatus,
+}
+
+thread_local! {
+    static INVOICES: RefCell<BTreeMap<u64, Invoice>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_INVOICE_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+#[update]
+fn create_invoice(amount: u128, memo: String, expiry: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    if memo.len() > MAX_MEMO_LENGTH {
+        return Err(TransferError::MemoTooLong);
+    }
+    let now = get_time();
+    if expiry <= now {
+        return Err(TransferError::InvalidDeadline);
+    }
+
+    let id = NEXT_INVOICE_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    INVOICES.with(|invoices| {
+        invoices.borrow_mut().insert(id, Invoice {
+            id,
+            merchant: caller,
+            amount,
+            memo,
+            created_at: now,
+            expiry,
+            status: InvoiceStatus::Open,
+        });
+    });
+    Ok(id)
+}
+
+// Pays the full invoice amount to the merchant in a single transfer and returns its block index. There is no
+// way to pay part of an invoice: without the full amount available the transfer fails and the invoice stays open.
+#[update]
+fn pay_invoice(invoice_id: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    let now = get_time();
+    let (merchant, amount) = INVOICES.with(|invoices| {
+        let invoices = invoices.borrow();
+        let invoice = invoices.get(&invoice_id).ok_or(TransferError::InvoiceNotFound)?;
+        if invoice.status != InvoiceStatus::Open {
+            return Err(TransferError::InvoiceAlreadyPaid);
+        }
+        if now >= invoice.expiry {
+            return Err(TransferError::InvoiceExpired);
+        }
+        Ok((invoice.merchant, invoice.amount))
+    })?;
+
+    let block_index = transfer_from(caller, merchant, amount)?;
+    INVOICES.with(|invoices| {
+        let mut invoices = invoices.borrow_mut();
+        invoices.get_mut(&invoice_id).unwrap().status = InvoiceStatus::Paid {
+            payer: caller,
+            block_index,
+            paid_at: now,
+        };
+    });
+    Ok(block_index)
+}
+
+#[query]
+fn get_invoice(invoice_id: u64) -> Option<Invoice> {
+    INVOICES.with(|invoices| invoices.borrow().get(&invoice_id).cloned())
+}
+
+// Invoices created by `merchant`, oldest first. Pass the last id of a page as `start_after` to fetch the next one.
+#[query]
+fn get_invoices(merchant: Principal, start_after: Option<u64>, limit: u32) -> Vec<Invoice> {
+    // Nothing comes after the largest id.
+    let Some(start) = start_after.map_or(Some(0), |id| id.checked_add(1)) else {
+        return Vec::new();
+    };
+    INVOICES.with(|invoices| {
+        invoices.borrow()
+            .range(start..)
+            .map(|(_, invoice)| invoice)
+            .filter(|invoice| invoice.merchant == merchant)
+            .take((limit as usize).min(MAX_INVOICES_PAGE))
+            .cloned()
+            .collect()
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        INVOICES.with(|invoices| invoices.take()),
+        NEXT_INVOICE_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (invoices, next_id) = candid::decode_one(bytes)?;
+    INVOICES.with(|state| *state.borrow_mut() = invoices);
+    NEXT_INVOICE_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    INVOICES.with(|invoices| invoices.borrow_mut().clear());
+    NEXT_INVOICE_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, get_transfer_history, mint, reset_state, test_utils, Operation, OWNER};
+
+    #[test]
+    fn test_pay_invoice_once() {
+        reset_state();
+        let payer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let merchant = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = payer);
+        test_utils::set_caller(payer);
+        assert!(mint(payer, 500).is_ok());
+
+        test_utils::set_caller(merchant);
+        assert!(matches!(create_invoice(100, "x".repeat(257), 100), Err(TransferError::MemoTooLong)));
+        assert!(matches!(create_invoice(300, "order #1".to_string(), 0), Err(TransferError::InvalidDeadline)));
+        let paid = create_invoice(300, "order #1".to_string(), 100).unwrap();
+        let too_large = create_invoice(300, "order #2".to_string(), 100).unwrap();
+        let expiring = create_invoice(100, "order #3".to_string(), 100).unwrap();
+
+        test_utils::set_caller(payer);
+        let block_index = pay_invoice(paid).unwrap();
+        assert!(matches!(pay_invoice(paid), Err(TransferError::InvoiceAlreadyPaid)));
+        // Only 200 is left, so nothing of the second invoice is paid.
+        assert!(matches!(pay_invoice(too_large), Err(TransferError::InsufficientBalance)));
+        assert_eq!(get_invoice(too_large).unwrap().status, InvoiceStatus::Open);
+        test_utils::set_time(100);
+        assert!(matches!(pay_invoice(expiring), Err(TransferError::InvoiceExpired)));
+
+        assert_eq!(balance_of(&merchant), 300);
+        assert_eq!(get_invoice(paid).unwrap().status, InvoiceStatus::Paid { payer, block_index, paid_at: 0 });
+        let event = &get_transfer_history()[block_index as usize];
+        assert_eq!((event.operation.clone(), event.amount), (Operation::Transfer, 300));
+    }
+
+    #[test]
+    fn test_invoices_pagination() {
+        reset_state();
+        let merchant = Principal::from_text("aaaaa-aa").unwrap();
+        let other = Principal::anonymous();
+        for i in 0..5 {
+            test_utils::set_caller(if i % 2 == 0 { merchant } else { other });
+            create_invoice(10, String::new(), 100).unwrap();
+        }
+
+        let ids = |page: Vec<Invoice>| page.iter().map(|invoice| invoice.id).collect::<Vec<_>>();
+        assert_eq!(ids(get_invoices(merchant, None, 2)), vec![0, 2]);
+        assert_eq!(ids(get_invoices(merchant, Some(2), 2)), vec![4]);
+        assert_eq!(ids(get_invoices(other, None, 10)), vec![1, 3]);
+        assert!(get_invoices(merchant, Some(u64::MAX), 10).is_empty());
+    }
+}
@@ -0,0 +1,7 @@
+// Code shared by the canister and the offline tools in `src/bin`.
+
+pub mod account_identifier;
+pub mod account_text;
+pub mod amount;
+pub mod icp_ledger;
+pub mod merkle;
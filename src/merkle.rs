@@ -0,0 +1,95 @@
+// Merkle tree over `(account, amount)` airdrop entries. Leaves and inner nodes use different prefixes so an inner
+// node can never pass as a leaf, and each pair of children is hashed in sorted order so proofs need no
+// left/right flags.
+
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub fn leaf_hash(account: &Principal, amount: u128) -> Hash {
+    let account = account.as_slice();
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX, account.len() as u8]);
+    hasher.update(account);
+    hasher.update(amount.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(a: &Hash, b: &Hash) -> Hash {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(first);
+    hasher.update(second);
+    hasher.finalize().into()
+}
+
+pub fn verify(root: &Hash, leaf: Hash, proof: &[Hash]) -> bool {
+    let computed = proof.iter().fold(leaf, |hash, sibling| node_hash(&hash, sibling));
+    computed == *root
+}
+
+pub struct MerkleTree {
+    // `levels[0]` holds the leaves, the last level holds the root.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> MerkleTree {
+        assert!(!leaves.is_empty(), "A Merkle tree needs at least one leaf");
+        let mut levels = vec![leaves];
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => node_hash(a, b),
+                    // An odd node out moves up unchanged.
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub fn proof(&self, mut index: usize) -> Vec<Hash> {
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        proof
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_proof_verifies() {
+        for size in 1..=9u8 {
+            let entries: Vec<(Principal, u128)> = (0..size)
+                .map(|i| (Principal::from_slice(&[i]), 100 + i as u128))
+                .collect();
+            let tree = MerkleTree::new(entries.iter().map(|(account, amount)| leaf_hash(account, *amount)).collect());
+
+            for (index, (account, amount)) in entries.iter().enumerate() {
+                let proof = tree.proof(index);
+                assert!(verify(&tree.root(), leaf_hash(account, *amount), &proof));
+                assert!(!verify(&tree.root(), leaf_hash(account, *amount + 1), &proof));
+            }
+        }
+    }
+}
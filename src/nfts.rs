@@ -0,0 +1,961 @@
+// A single NFT collection following ICRC-7, with ICRC-37 approvals. NFTs are held in the same wallets as the
+// fungible tokens: `Wallet.nfts` lists the token ids a wallet holds and `NFTS` records the owner of each.
+//
+// Wallets are keyed by principal, so only accounts with the default subaccount can hold, send or spend NFTs.
+// `created_at_time` is accepted but not used for deduplication.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::icp_ledger::Account;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use crate::{get_caller, get_time, is_owner, tokens, with_wallet, MetadataValue, TransferError, WALLETS};
+
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 100;
+const DEFAULT_TAKE_VALUE: usize = 100;
+const MAX_TAKE_VALUE: usize = 1000;
+const MAX_MEMO_SIZE: usize = 32;
+const MAX_APPROVALS: usize = 10;
+
+pub(crate) const STANDARDS: &[(&str, &str)] = &[
+    ("ICRC-7", "https://github.com/dfinity/ICRC/ICRCs/ICRC-7"),
+    ("ICRC-37", "https://github.com/dfinity/ICRC/ICRCs/ICRC-37"),
+];
+
+// Set by the canister owner with `set_nft_collection`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct NftCollection {
+    name: String,
+    symbol: String,
+    description: Option<String>,
+    // URL or data URL of the collection's logo.
+    logo: Option<String>,
+    supply_cap: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ApprovalInfo {
+    pub(crate) spender: Account,
+    pub(crate) from_subaccount: Option<Vec<u8>>,
+    // The approval is ignored from this time on.
+    pub(crate) expires_at: Option<u64>,
+    pub(crate) memo: Option<Vec<u8>>,
+    pub(crate) created_at_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Nft {
+    owner: Principal,
+    metadata: Vec<(String, MetadataValue)>,
+    // Cleared whenever the NFT changes hands.
+    approvals: Vec<ApprovalInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum NftOperation {
+    Mint,
+    Transfer,
+    TransferFrom { spender: Account },
+    Approve { spender: Account },
+    ApproveCollection { spender: Account },
+    Revoke { spender: Option<Account> },
+    RevokeCollection { spender: Option<Account> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct NftEvent {
+    operation: NftOperation,
+    // `None` for collection approvals.
+    token_id: Option<Nat>,
+    from: Principal,
+    // Set when the NFT changes hands.
+    to: Option<Principal>,
+    memo: Option<Vec<u8>>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct NftTransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum NftTransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct ApproveTokenArg {
+    pub(crate) token_id: Nat,
+    pub(crate) approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub(crate) struct ApproveCollectionArg {
+    pub(crate) approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum ApproveError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RevokeTokenApprovalArg {
+    // `None` revokes every approval of the token.
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RevokeCollectionApprovalArg {
+    // `None` revokes every collection approval of the caller.
+    spender: Option<Account>,
+    from_subaccount: Option<Vec<u8>>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum RevokeError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct IsApprovedArg {
+    spender: Account,
+    from_subaccount: Option<Vec<u8>>,
+    token_id: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct TokenApproval {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct NftTransferFromArg {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Everything a wallet holds.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct WalletAssets {
+    balances: Vec<(String, u128)>,
+    nfts: Vec<Nat>,
+}
+
+fn default_collection() -> NftCollection {
+    NftCollection {
+        name: "ICP Token Items".to_string(),
+        symbol: "ICPTI".to_string(),
+        description: None,
+        logo: None,
+        supply_cap: None,
+    }
+}
+
+thread_local! {
+    static COLLECTION: RefCell<NftCollection> = RefCell::new(default_collection());
+    static NFTS: RefCell<BTreeMap<Nat, Nft>> = const { RefCell::new(BTreeMap::new()) };
+    // Approvals to spend any NFT of the owning wallet, including ones it receives later.
+    static COLLECTION_APPROVALS: RefCell<HashMap<Principal, Vec<ApprovalInfo>>> = RefCell::new(HashMap::new());
+    // Indexed by the transaction index the ICRC-7 and ICRC-37 updates return.
+    static NFT_EVENTS: RefCell<Vec<NftEvent>> = const { RefCell::new(Vec::new()) };
+    static NEXT_NFT_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+pub(crate) fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    match subaccount {
+        None => true,
+        Some(subaccount) => subaccount.len() == 32 && subaccount.iter().all(|byte| *byte == 0),
+    }
+}
+
+// The wallet an account stands for, if it has the default subaccount.
+fn wallet_owner(account: &Account) -> Option<Principal> {
+    is_default_subaccount(&account.subaccount).then_some(account.owner)
+}
+
+// Spells the default subaccount as `None`, so that approvals compare equal however the spender was written.
+fn normalized(account: &Account) -> Account {
+    match wallet_owner(account) {
+        Some(owner) => Account::new(owner),
+        None => account.clone(),
+    }
+}
+
+fn generic_error(message: &str) -> (Nat, String) {
+    (Nat::from(0u64), message.to_string())
+}
+
+fn memo_too_long(memo: &Option<Vec<u8>>) -> bool {
+    memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+}
+
+fn is_live(approval: &ApprovalInfo) -> bool {
+    !matches!(approval.expires_at, Some(expires_at) if expires_at <= get_time())
+}
+
+fn check_query_batch(len: usize) {
+    if len > MAX_QUERY_BATCH_SIZE {
+        ic_cdk::trap(&format!("at most {} items per query", MAX_QUERY_BATCH_SIZE));
+    }
+}
+
+fn take_count(take: Option<Nat>) -> usize {
+    take.map_or(DEFAULT_TAKE_VALUE, |take| usize::try_from(take.0).unwrap_or(MAX_TAKE_VALUE).min(MAX_TAKE_VALUE))
+}
+
+fn owner_of(token_id: &Nat) -> Option<Principal> {
+    NFTS.with(|nfts| nfts.borrow().get(token_id).map(|nft| nft.owner))
+}
+
+fn record(event: NftEvent) -> Nat {
+    NFT_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        events.push(event);
+        Nat::from(events.len() - 1)
+    })
+}
+
+fn total_supply() -> u64 {
+    NFTS.with(|nfts| nfts.borrow().len() as u64)
+}
+
+// Hands the NFT to `to`, dropping the approvals the previous owner granted on it.
+fn move_nft(token_id: &Nat, from: Principal, to: Principal) {
+    NFTS.with(|nfts| {
+        if let Some(nft) = nfts.borrow_mut().get_mut(token_id) {
+            nft.owner = to;
+            nft.approvals.clear();
+        }
+    });
+    with_wallet(from, |wallet| wallet.nfts.remove(token_id));
+    with_wallet(to, |wallet| wallet.nfts.insert(token_id.clone()));
+}
+
+fn is_approved(spender: &Account, owner: Principal, token_id: &Nat) -> bool {
+    let spender = normalized(spender);
+    let approves = |approvals: &[ApprovalInfo]| {
+        approvals.iter().any(|approval| approval.spender == spender && is_live(approval))
+    };
+    NFTS.with(|nfts| nfts.borrow().get(token_id).is_some_and(|nft| approves(&nft.approvals)))
+        || COLLECTION_APPROVALS.with(|approvals| approvals.borrow().get(&owner).is_some_and(|approvals| approves(approvals)))
+}
+
+// Adds or replaces the approval of `approval.spender` in `approvals`.
+fn upsert_approval(approvals: &mut Vec<ApprovalInfo>, approval: ApprovalInfo) -> Result<(), (Nat, String)> {
+    approvals.retain(|existing| is_live(existing) && existing.spender != approval.spender);
+    if approvals.len() >= MAX_APPROVALS {
+        return Err(generic_error("too many approvals"));
+    }
+    approvals.push(approval);
+    Ok(())
+}
+
+// Checks an approval request from `caller` and normalizes its spender.
+fn valid_approval(caller: Principal, mut approval: ApprovalInfo) -> Result<ApprovalInfo, ApproveError> {
+    if !is_default_subaccount(&approval.from_subaccount) {
+        return Err(ApproveError::Unauthorized);
+    }
+    if approval.spender.owner == caller {
+        return Err(ApproveError::InvalidSpender);
+    }
+    if memo_too_long(&approval.memo) {
+        let (error_code, message) = generic_error("memo too long");
+        return Err(ApproveError::GenericError { error_code, message });
+    }
+    if !is_live(&approval) {
+        let (error_code, message) = generic_error("approval already expired");
+        return Err(ApproveError::GenericError { error_code, message });
+    }
+    approval.spender = normalized(&approval.spender);
+    approval.from_subaccount = None;
+    approval.created_at_time = get_time();
+    Ok(approval)
+}
+
+#[update]
+fn set_nft_collection(collection: NftCollection) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    if collection.supply_cap.is_some_and(|cap| cap < total_supply()) {
+        return Err(TransferError::InvalidAmount);
+    }
+    COLLECTION.with(|current| *current.borrow_mut() = collection);
+    Ok(())
+}
+
+// Creates an NFT in the wallet of `to` and returns its token id.
+#[update]
+fn mint_nft(to: Principal, metadata: Vec<(String, MetadataValue)>) -> Result<Nat, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    let cap = COLLECTION.with(|collection| collection.borrow().supply_cap);
+    if cap.is_some_and(|cap| total_supply() >= cap) {
+        return Err(TransferError::SupplyCapReached);
+    }
+
+    let token_id = NEXT_NFT_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        Nat::from(id)
+    });
+    NFTS.with(|nfts| nfts.borrow_mut().insert(token_id.clone(), Nft { owner: to, metadata, approvals: Vec::new() }));
+    with_wallet(to, |wallet| wallet.nfts.insert(token_id.clone()));
+    record(NftEvent {
+        operation: NftOperation::Mint,
+        token_id: Some(token_id.clone()),
+        from: get_caller(),
+        to: Some(to),
+        memo: None,
+        timestamp: get_time(),
+    });
+    Ok(token_id)
+}
+
+#[query]
+fn icrc7_name() -> String {
+    COLLECTION.with(|collection| collection.borrow().name.clone())
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    COLLECTION.with(|collection| collection.borrow().symbol.clone())
+}
+
+#[query]
+fn icrc7_description() -> Option<String> {
+    COLLECTION.with(|collection| collection.borrow().description.clone())
+}
+
+#[query]
+fn icrc7_logo() -> Option<String> {
+    COLLECTION.with(|collection| collection.borrow().logo.clone())
+}
+
+#[query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(total_supply())
+}
+
+#[query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    COLLECTION.with(|collection| collection.borrow().supply_cap.map(Nat::from))
+}
+
+#[query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE_VALUE))
+}
+
+#[query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+#[query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS))
+}
+
+#[query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[query]
+fn icrc7_collection_metadata() -> Vec<(String, MetadataValue)> {
+    let collection = COLLECTION.with(|collection| collection.borrow().clone());
+    let nat = |value: usize| MetadataValue::Nat(Nat::from(value));
+    let mut metadata = vec![
+        ("icrc7:name".to_string(), MetadataValue::Text(collection.name)),
+        ("icrc7:symbol".to_string(), MetadataValue::Text(collection.symbol)),
+        ("icrc7:total_supply".to_string(), MetadataValue::Nat(Nat::from(total_supply()))),
+    ];
+    if let Some(description) = collection.description {
+        metadata.push(("icrc7:description".to_string(), MetadataValue::Text(description)));
+    }
+    if let Some(logo) = collection.logo {
+        metadata.push(("icrc7:logo".to_string(), MetadataValue::Text(logo)));
+    }
+    if let Some(supply_cap) = collection.supply_cap {
+        metadata.push(("icrc7:supply_cap".to_string(), MetadataValue::Nat(Nat::from(supply_cap))));
+    }
+    metadata.extend([
+        ("icrc7:max_query_batch_size".to_string(), nat(MAX_QUERY_BATCH_SIZE)),
+        ("icrc7:max_update_batch_size".to_string(), nat(MAX_UPDATE_BATCH_SIZE)),
+        ("icrc7:default_take_value".to_string(), nat(DEFAULT_TAKE_VALUE)),
+        ("icrc7:max_take_value".to_string(), nat(MAX_TAKE_VALUE)),
+        ("icrc7:max_memo_size".to_string(), nat(MAX_MEMO_SIZE)),
+        ("icrc37:max_approvals_per_token_or_collection".to_string(), nat(MAX_APPROVALS)),
+        ("icrc37:max_revoke_approvals".to_string(), nat(MAX_UPDATE_BATCH_SIZE)),
+    ]);
+    metadata
+}
+
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, MetadataValue)>>> {
+    check_query_batch(token_ids.len());
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        token_ids.iter().map(|token_id| nfts.get(token_id).map(|nft| nft.metadata.clone())).collect()
+    })
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    check_query_batch(token_ids.len());
+    token_ids.iter().map(|token_id| owner_of(token_id).map(Account::new)).collect()
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    check_query_batch(accounts.len());
+    accounts.iter()
+        .map(|account| {
+            let held = wallet_owner(account).and_then(|owner| {
+                WALLETS.with(|wallets| wallets.borrow().get(&owner).map(|wallet| wallet.nfts.len()))
+            });
+            Nat::from(held.unwrap_or(0))
+        })
+        .collect()
+}
+
+// Token ids in ascending order, starting after `prev`.
+#[query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+    NFTS.with(|nfts| nfts.borrow().range((start, Bound::Unbounded)).map(|(id, _)| id.clone()).take(take_count(take)).collect())
+}
+
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let Some(owner) = wallet_owner(&account) else {
+        return Vec::new();
+    };
+    let start = prev.map_or(Bound::Unbounded, Bound::Excluded);
+    WALLETS.with(|wallets| {
+        wallets.borrow()
+            .get(&owner)
+            .map(|wallet| wallet.nfts.range((start, Bound::Unbounded)).take(take_count(take)).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+fn transfer_nft(caller: Principal, arg: NftTransferArg) -> Result<Nat, NftTransferError> {
+    if memo_too_long(&arg.memo) {
+        let (error_code, message) = generic_error("memo too long");
+        return Err(NftTransferError::GenericError { error_code, message });
+    }
+    let owner = owner_of(&arg.token_id).ok_or(NftTransferError::NonExistingTokenId)?;
+    if owner != caller || !is_default_subaccount(&arg.from_subaccount) {
+        return Err(NftTransferError::Unauthorized);
+    }
+    let to = wallet_owner(&arg.to).ok_or(NftTransferError::InvalidRecipient)?;
+    if to == owner {
+        return Err(NftTransferError::InvalidRecipient);
+    }
+
+    move_nft(&arg.token_id, owner, to);
+    Ok(record(NftEvent {
+        operation: NftOperation::Transfer,
+        token_id: Some(arg.token_id),
+        from: owner,
+        to: Some(to),
+        memo: arg.memo,
+        timestamp: get_time(),
+    }))
+}
+
+// Each entry succeeds or fails on its own; the results are in the order of `args`.
+#[update]
+fn icrc7_transfer(args: Vec<NftTransferArg>) -> Vec<Option<Result<Nat, NftTransferError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(NftTransferError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(transfer_nft(caller, arg))).collect()
+}
+
+fn approve_token(caller: Principal, arg: ApproveTokenArg) -> Result<Nat, ApproveError> {
+    let owner = owner_of(&arg.token_id).ok_or(ApproveError::NonExistingTokenId)?;
+    if owner != caller {
+        return Err(ApproveError::Unauthorized);
+    }
+    let approval = valid_approval(caller, arg.approval_info)?;
+    let operation = NftOperation::Approve { spender: approval.spender.clone() };
+    let memo = approval.memo.clone();
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&arg.token_id).ok_or(ApproveError::NonExistingTokenId)?;
+        upsert_approval(&mut nft.approvals, approval)
+            .map_err(|(error_code, message)| ApproveError::GenericError { error_code, message })
+    })?;
+    Ok(record(NftEvent { operation, token_id: Some(arg.token_id), from: caller, to: None, memo, timestamp: get_time() }))
+}
+
+#[update]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<Result<Nat, ApproveError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(ApproveError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(approve_token(caller, arg))).collect()
+}
+
+fn approve_collection(caller: Principal, arg: ApproveCollectionArg) -> Result<Nat, ApproveError> {
+    let approval = valid_approval(caller, arg.approval_info)?;
+    let operation = NftOperation::ApproveCollection { spender: approval.spender.clone() };
+    let memo = approval.memo.clone();
+    COLLECTION_APPROVALS.with(|approvals| {
+        upsert_approval(approvals.borrow_mut().entry(caller).or_default(), approval)
+            .map_err(|(error_code, message)| ApproveError::GenericError { error_code, message })
+    })?;
+    Ok(record(NftEvent { operation, token_id: None, from: caller, to: None, memo, timestamp: get_time() }))
+}
+
+#[update]
+fn icrc37_approve_collection(args: Vec<ApproveCollectionArg>) -> Vec<Option<Result<Nat, ApproveError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(ApproveError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(approve_collection(caller, arg))).collect()
+}
+
+// Removes the approval of `spender`, or all approvals if `spender` is `None`.
+fn revoke(approvals: &mut Vec<ApprovalInfo>, spender: &Option<Account>) -> Result<(), RevokeError> {
+    match spender {
+        None => approvals.clear(),
+        Some(spender) => {
+            let spender = normalized(spender);
+            let before = approvals.len();
+            approvals.retain(|approval| approval.spender != spender);
+            if approvals.len() == before {
+                return Err(RevokeError::ApprovalDoesNotExist);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn revoke_token_approval(caller: Principal, arg: RevokeTokenApprovalArg) -> Result<Nat, RevokeError> {
+    let owner = owner_of(&arg.token_id).ok_or(RevokeError::NonExistingTokenId)?;
+    if owner != caller || !is_default_subaccount(&arg.from_subaccount) {
+        return Err(RevokeError::Unauthorized);
+    }
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        let nft = nfts.get_mut(&arg.token_id).ok_or(RevokeError::NonExistingTokenId)?;
+        revoke(&mut nft.approvals, &arg.spender)
+    })?;
+    Ok(record(NftEvent {
+        operation: NftOperation::Revoke { spender: arg.spender },
+        token_id: Some(arg.token_id),
+        from: caller,
+        to: None,
+        memo: arg.memo,
+        timestamp: get_time(),
+    }))
+}
+
+#[update]
+fn icrc37_revoke_token_approvals(args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<Result<Nat, RevokeError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(RevokeError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(revoke_token_approval(caller, arg))).collect()
+}
+
+fn revoke_collection_approval(caller: Principal, arg: RevokeCollectionApprovalArg) -> Result<Nat, RevokeError> {
+    if !is_default_subaccount(&arg.from_subaccount) {
+        return Err(RevokeError::Unauthorized);
+    }
+    COLLECTION_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        let approved = approvals.get_mut(&caller).ok_or(RevokeError::ApprovalDoesNotExist)?;
+        revoke(approved, &arg.spender)?;
+        if approved.is_empty() {
+            approvals.remove(&caller);
+        }
+        Ok(())
+    })?;
+    Ok(record(NftEvent {
+        operation: NftOperation::RevokeCollection { spender: arg.spender },
+        token_id: None,
+        from: caller,
+        to: None,
+        memo: arg.memo,
+        timestamp: get_time(),
+    }))
+}
+
+#[update]
+fn icrc37_revoke_collection_approvals(args: Vec<RevokeCollectionApprovalArg>) -> Vec<Option<Result<Nat, RevokeError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(RevokeError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(revoke_collection_approval(caller, arg))).collect()
+}
+
+#[query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    check_query_batch(args.len());
+    args.iter()
+        .map(|arg| {
+            is_default_subaccount(&arg.from_subaccount)
+                && owner_of(&arg.token_id).is_some_and(|owner| is_approved(&arg.spender, owner, &arg.token_id))
+        })
+        .collect()
+}
+
+// Live approvals of the NFT, ordered by when they were granted, starting after the approval of `prev`'s spender.
+// Index after the approval of `prev`, compared in normalized form; `None` when `prev` is not in the list.
+fn page_start(approvals: &[ApprovalInfo], prev: Option<&Account>) -> Option<usize> {
+    let Some(prev) = prev else {
+        return Some(0);
+    };
+    let prev = normalized(prev);
+    approvals.iter().position(|approval| approval.spender == prev).map(|index| index + 1)
+}
+
+#[query]
+fn icrc37_get_token_approvals(token_id: Nat, prev: Option<TokenApproval>, take: Option<Nat>) -> Vec<TokenApproval> {
+    let approvals = NFTS.with(|nfts| nfts.borrow().get(&token_id).map(|nft| nft.approvals.clone()).unwrap_or_default());
+    let Some(start) = page_start(&approvals, prev.as_ref().map(|prev| &prev.approval_info.spender)) else {
+        return Vec::new();
+    };
+    approvals.into_iter()
+        .skip(start)
+        .filter(is_live)
+        .take(take_count(take))
+        .map(|approval_info| TokenApproval { token_id: token_id.clone(), approval_info })
+        .collect()
+}
+
+#[query]
+fn icrc37_get_collection_approvals(owner: Account, prev: Option<ApprovalInfo>, take: Option<Nat>) -> Vec<ApprovalInfo> {
+    let Some(owner) = wallet_owner(&owner) else {
+        return Vec::new();
+    };
+    let approvals = COLLECTION_APPROVALS.with(|approvals| approvals.borrow().get(&owner).cloned().unwrap_or_default());
+    let Some(start) = page_start(&approvals, prev.as_ref().map(|prev| &prev.spender)) else {
+        return Vec::new();
+    };
+    approvals.into_iter()
+        .skip(start)
+        .filter(is_live)
+        .take(take_count(take))
+        .collect()
+}
+
+fn transfer_nft_from(caller: Principal, arg: NftTransferFromArg) -> Result<Nat, NftTransferError> {
+    if memo_too_long(&arg.memo) {
+        let (error_code, message) = generic_error("memo too long");
+        return Err(NftTransferError::GenericError { error_code, message });
+    }
+    let owner = owner_of(&arg.token_id).ok_or(NftTransferError::NonExistingTokenId)?;
+    let spender = Account { owner: caller, subaccount: arg.spender_subaccount };
+    if wallet_owner(&arg.from) != Some(owner) || !is_approved(&spender, owner, &arg.token_id) {
+        return Err(NftTransferError::Unauthorized);
+    }
+    let to = wallet_owner(&arg.to).ok_or(NftTransferError::InvalidRecipient)?;
+    if to == owner {
+        return Err(NftTransferError::InvalidRecipient);
+    }
+
+    move_nft(&arg.token_id, owner, to);
+    Ok(record(NftEvent {
+        operation: NftOperation::TransferFrom { spender: normalized(&spender) },
+        token_id: Some(arg.token_id),
+        from: owner,
+        to: Some(to),
+        memo: arg.memo,
+        timestamp: get_time(),
+    }))
+}
+
+#[update]
+fn icrc37_transfer_from(args: Vec<NftTransferFromArg>) -> Vec<Option<Result<Nat, NftTransferError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = generic_error("batch too large");
+        return vec![Some(Err(NftTransferError::GenericBatchError { error_code, message }))];
+    }
+    let caller = get_caller();
+    args.into_iter().map(|arg| Some(transfer_nft_from(caller, arg))).collect()
+}
+
+#[query]
+fn get_nft_history(token_id: Nat) -> Vec<NftEvent> {
+    NFT_EVENTS.with(|events| {
+        events.borrow()
+            .iter()
+            .filter(|event| event.token_id.as_ref() == Some(&token_id))
+            .cloned()
+            .collect()
+    })
+}
+
+// Fungible balances and NFTs of a wallet in one call.
+#[query]
+fn get_wallet_assets(owner: Principal) -> WalletAssets {
+    let nfts = WALLETS.with(|wallets| {
+        wallets.borrow().get(&owner).map(|wallet| wallet.nfts.iter().cloned().collect()).unwrap_or_default()
+    });
+    WalletAssets { balances: tokens::get_balances(owner), nfts }
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        COLLECTION.with(|collection| collection.replace(default_collection())),
+        NFTS.with(|nfts| nfts.take()),
+        COLLECTION_APPROVALS.with(|approvals| approvals.take()),
+        NFT_EVENTS.with(|events| events.take()),
+        NEXT_NFT_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (collection, nfts, approvals, events, next_id) = candid::decode_one(bytes)?;
+    COLLECTION.with(|state| *state.borrow_mut() = collection);
+    NFTS.with(|state| *state.borrow_mut() = nfts);
+    COLLECTION_APPROVALS.with(|state| *state.borrow_mut() = approvals);
+    NFT_EVENTS.with(|state| *state.borrow_mut() = events);
+    NEXT_NFT_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    COLLECTION.with(|collection| *collection.borrow_mut() = default_collection());
+    NFTS.with(|nfts| nfts.borrow_mut().clear());
+    COLLECTION_APPROVALS.with(|approvals| approvals.borrow_mut().clear());
+    NFT_EVENTS.with(|events| events.borrow_mut().clear());
+    NEXT_NFT_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_wallet, mint, reset_state, test_utils, OWNER};
+
+    fn transfer_arg(to: Principal, token_id: u64) -> NftTransferArg {
+        NftTransferArg { from_subaccount: None, to: Account::new(to), token_id: Nat::from(token_id), memo: None, created_at_time: None }
+    }
+
+    fn approval(spender: Principal, expires_at: Option<u64>) -> ApprovalInfo {
+        ApprovalInfo { spender: Account::new(spender), from_subaccount: None, expires_at, memo: None, created_at_time: 0 }
+    }
+
+    fn transfer_from_arg(from: Principal, to: Principal, token_id: u64) -> NftTransferFromArg {
+        NftTransferFromArg {
+            spender_subaccount: None,
+            from: Account::new(from),
+            to: Account::new(to),
+            token_id: Nat::from(token_id),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    #[test]
+    fn test_mint_and_transfer() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let player = Principal::from_text("aaaaa-aa").unwrap();
+        let other = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(player);
+        assert!(create_wallet().is_ok());
+        assert!(matches!(mint_nft(player, Vec::new()), Err(TransferError::Unauthorized)));
+
+        test_utils::set_caller(owner);
+        let sword = vec![("name".to_string(), MetadataValue::Text("Sword".to_string()))];
+        assert_eq!(mint_nft(player, sword.clone()).unwrap(), Nat::from(0u64));
+        assert_eq!(mint_nft(player, Vec::new()).unwrap(), Nat::from(1u64));
+        assert!(mint(player, 500).is_ok());
+        assert!(set_nft_collection(NftCollection { supply_cap: Some(2), ..default_collection() }).is_ok());
+        assert!(matches!(mint_nft(player, Vec::new()), Err(TransferError::SupplyCapReached)));
+
+        assert_eq!(icrc7_total_supply(), Nat::from(2u64));
+        assert_eq!(icrc7_token_metadata(vec![Nat::from(0u64), Nat::from(7u64)]), vec![Some(sword), None]);
+        assert_eq!(get_wallet_assets(player), WalletAssets {
+            balances: vec![("ICPT".to_string(), 500)],
+            nfts: vec![Nat::from(0u64), Nat::from(1u64)],
+        });
+
+        let results = icrc7_transfer(vec![transfer_arg(other, 0), transfer_arg(other, 9)]);
+        assert_eq!(results, vec![Some(Err(NftTransferError::Unauthorized)), Some(Err(NftTransferError::NonExistingTokenId))]);
+
+        test_utils::set_caller(player);
+        let mut to_subaccount = transfer_arg(other, 1);
+        to_subaccount.to.subaccount = Some(vec![1; 32]);
+        let results = icrc7_transfer(vec![transfer_arg(other, 0), transfer_arg(player, 1), to_subaccount]);
+        assert_eq!(results, vec![
+            Some(Ok(Nat::from(2u64))),
+            Some(Err(NftTransferError::InvalidRecipient)),
+            Some(Err(NftTransferError::InvalidRecipient)),
+        ]);
+
+        assert_eq!(icrc7_owner_of(vec![Nat::from(0u64), Nat::from(1u64)]), vec![Some(Account::new(other)), Some(Account::new(player))]);
+        assert_eq!(icrc7_balance_of(vec![Account::new(player), Account::new(other)]), vec![Nat::from(1u64), Nat::from(1u64)]);
+        assert_eq!(icrc7_tokens_of(Account::new(player), None, None), vec![Nat::from(1u64)]);
+        assert_eq!(icrc7_tokens(Some(Nat::from(0u64)), None), vec![Nat::from(1u64)]);
+        assert_eq!(get_nft_history(Nat::from(0u64)).len(), 2);
+    }
+
+    #[test]
+    fn test_approvals() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let player = Principal::from_text("aaaaa-aa").unwrap();
+        let market = Principal::anonymous();
+        let buyer = Principal::from_slice(&[7]);
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint_nft(player, Vec::new()).is_ok());
+        assert!(mint_nft(player, Vec::new()).is_ok());
+
+        test_utils::set_caller(player);
+        test_utils::set_time(100);
+        let approve = |token_id: u64, approval_info| ApproveTokenArg { token_id: Nat::from(token_id), approval_info };
+        let results = icrc37_approve_tokens(vec![
+            approve(0, approval(market, None)),
+            approve(0, approval(player, None)),
+            approve(1, approval(market, Some(100))),
+        ]);
+        assert!(matches!(results[0], Some(Ok(_))));
+        assert_eq!(results[1], Some(Err(ApproveError::InvalidSpender)));
+        assert!(matches!(results[2], Some(Err(ApproveError::GenericError { .. }))));
+
+        // Spending a token approval drops it, so the new owner's NFT cannot be taken again.
+        test_utils::set_caller(market);
+        let is_approved = |token_id: u64| icrc37_is_approved(vec![IsApprovedArg {
+            spender: Account::new(market),
+            from_subaccount: None,
+            token_id: Nat::from(token_id),
+        }]);
+        assert_eq!(is_approved(0), vec![true]);
+        let results = icrc37_transfer_from(vec![transfer_from_arg(player, buyer, 0), transfer_from_arg(player, buyer, 1)]);
+        assert!(matches!(results[0], Some(Ok(_))));
+        assert_eq!(results[1], Some(Err(NftTransferError::Unauthorized)));
+        assert_eq!(icrc7_owner_of(vec![Nat::from(0u64)]), vec![Some(Account::new(buyer))]);
+        assert_eq!(is_approved(0), vec![false]);
+        assert!(icrc37_get_token_approvals(Nat::from(0u64), None, None).is_empty());
+
+        // A collection approval covers every NFT of the wallet until it expires or is revoked.
+        test_utils::set_caller(player);
+        let results = icrc37_approve_collection(vec![ApproveCollectionArg { approval_info: approval(market, Some(200)) }]);
+        assert!(matches!(results[0], Some(Ok(_))));
+        assert_eq!(icrc37_get_collection_approvals(Account::new(player), None, None).len(), 1);
+        assert_eq!(is_approved(1), vec![true]);
+        test_utils::set_time(200);
+        assert_eq!(is_approved(1), vec![false]);
+
+        test_utils::set_time(150);
+        let revoke = |spender| RevokeCollectionApprovalArg { spender, from_subaccount: None, memo: None, created_at_time: None };
+        let results = icrc37_revoke_collection_approvals(vec![Some(Account::new(market)), Some(Account::new(buyer))]
+            .into_iter()
+            .map(revoke)
+            .collect());
+        assert!(matches!(results[0], Some(Ok(_))));
+        assert_eq!(results[1], Some(Err(RevokeError::ApprovalDoesNotExist)));
+        assert_eq!(is_approved(1), vec![false]);
+
+        // Revoking without any approval neither succeeds nor leaves an empty entry behind.
+        assert!(COLLECTION_APPROVALS.with(|approvals| approvals.borrow().is_empty()));
+        test_utils::set_caller(buyer);
+        assert_eq!(icrc37_revoke_collection_approvals(vec![revoke(None)]), vec![Some(Err(RevokeError::ApprovalDoesNotExist))]);
+        assert!(COLLECTION_APPROVALS.with(|approvals| approvals.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_approval_pages() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let player = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint_nft(player, Vec::new()).is_ok());
+
+        test_utils::set_caller(player);
+        let spenders: Vec<Principal> = (1..=3).map(|i| Principal::from_slice(&[i])).collect();
+        for spender in &spenders {
+            let approve = ApproveTokenArg { token_id: Nat::from(0u64), approval_info: approval(*spender, None) };
+            assert!(matches!(icrc37_approve_tokens(vec![approve])[0], Some(Ok(_))));
+            let approve = ApproveCollectionArg { approval_info: approval(*spender, None) };
+            assert!(matches!(icrc37_approve_collection(vec![approve])[0], Some(Ok(_))));
+        }
+
+        // `prev` with the explicit default subaccount is the same spender.
+        let mut prev = approval(spenders[0], None);
+        prev.spender.subaccount = Some(vec![0; 32]);
+        let page = icrc37_get_collection_approvals(Account::new(player), Some(prev.clone()), Some(Nat::from(1u64)));
+        assert_eq!(page.iter().map(|approval| approval.spender.owner).collect::<Vec<_>>(), vec![spenders[1]]);
+        let token_prev = TokenApproval { token_id: Nat::from(0u64), approval_info: prev };
+        let page = icrc37_get_token_approvals(Nat::from(0u64), Some(token_prev), None);
+        assert_eq!(page.iter().map(|approval| approval.approval_info.spender.owner).collect::<Vec<_>>(), spenders[1..]);
+
+        // An unknown `prev` ends the listing instead of restarting it.
+        let unknown = approval(Principal::from_slice(&[9]), None);
+        assert!(icrc37_get_collection_approvals(Account::new(player), Some(unknown.clone()), None).is_empty());
+        let token_prev = TokenApproval { token_id: Nat::from(0u64), approval_info: unknown };
+        assert!(icrc37_get_token_approvals(Nat::from(0u64), Some(token_prev), None).is_empty());
+    }
+}
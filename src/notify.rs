@@ -0,0 +1,194 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{get_caller, transfer_from, TransferError};
+
+// Argument of the `on_token_received` method recipients of `transfer_and_call` implement.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TokenReceived {
+    from: Principal,
+    amount: u128,
+    block_index: u64,
+    payload: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum CallbackStatus {
+    Pending,
+    Delivered,
+    // The recipient rejected or trapped; the transfer still stands.
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferAndCallResult {
+    block_index: u64,
+    callback: CallbackStatus,
+}
+
+thread_local! {
+    // Callers with a `transfer_and_call` waiting on its callback.
+    static CALLS_IN_FLIGHT: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    // Callback outcome per block index of the transfer.
+    static CALLBACKS: RefCell<BTreeMap<u64, CallbackStatus>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Rejects a second `transfer_and_call` from the same caller while the first one awaits its callback. Released
+// on drop, which also runs when the callback traps.
+struct CallGuard(Principal);
+
+impl CallGuard {
+    fn new(caller: Principal) -> Result<CallGuard, TransferError> {
+        if !CALLS_IN_FLIGHT.with(|calls| calls.borrow_mut().insert(caller)) {
+            return Err(TransferError::CallInProgress);
+        }
+        Ok(CallGuard(caller))
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        CALLS_IN_FLIGHT.with(|calls| calls.borrow_mut().remove(&self.0));
+    }
+}
+
+// The call to the recipient, behind a trait so the tests can run against in-memory recipients.
+trait Recipient {
+    async fn on_token_received(&self, to: Principal, notification: TokenReceived) -> Result<(), String>;
+}
+
+struct RecipientCanister;
+
+impl Recipient for RecipientCanister {
+    async fn on_token_received(&self, to: Principal, notification: TokenReceived) -> Result<(), String> {
+        ic_cdk::call::<_, ()>(to, "on_token_received", (notification,))
+            .await
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))
+    }
+}
+
+fn set_callback_status(block_index: u64, status: CallbackStatus) {
+    CALLBACKS.with(|callbacks| callbacks.borrow_mut().insert(block_index, status));
+}
+
+// Transfers to `to` and then calls `on_token_received` on it. The transfer is committed before the call goes
+// out and is never reverted: by the time the callback fails the recipient may already have acted on the funds.
+// A failed callback is recorded instead, see `get_callback_status`.
+async fn transfer_and_call_with(
+    recipient: &impl Recipient,
+    caller: Principal,
+    to: Principal,
+    amount: u128,
+    payload: Vec<u8>,
+) -> Result<TransferAndCallResult, TransferError> {
+    let _guard = CallGuard::new(caller)?;
+    let block_index = transfer_from(caller, to, amount)?;
+    set_callback_status(block_index, CallbackStatus::Pending);
+
+    let notification = TokenReceived { from: caller, amount, block_index, payload };
+    let callback = match recipient.on_token_received(to, notification).await {
+        Ok(()) => CallbackStatus::Delivered,
+        Err(reason) => CallbackStatus::Failed { reason },
+    };
+    set_callback_status(block_index, callback.clone());
+    Ok(TransferAndCallResult { block_index, callback })
+}
+
+#[update]
+async fn transfer_and_call(to: Principal, amount: u128, payload: Vec<u8>) -> Result<TransferAndCallResult, TransferError> {
+    transfer_and_call_with(&RecipientCanister, get_caller(), to, amount, payload).await
+}
+
+#[query]
+fn get_callback_status(block_index: u64) -> Option<CallbackStatus> {
+    CALLBACKS.with(|callbacks| callbacks.borrow().get(&block_index).cloned())
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(CALLBACKS.with(|callbacks| callbacks.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let callbacks = candid::decode_one(bytes)?;
+    CALLBACKS.with(|state| *state.borrow_mut() = callbacks);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    CALLS_IN_FLIGHT.with(|calls| calls.borrow_mut().clear());
+    CALLBACKS.with(|callbacks| callbacks.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, mint, reset_state, test_utils, OWNER};
+    use std::cell::Cell;
+    use test_utils::block_on;
+
+    // Recipient that keeps every notification and rejects them while `reject` is set.
+    #[derive(Default)]
+    struct MockRecipient {
+        received: RefCell<Vec<(Principal, TokenReceived)>>,
+        reject: Cell<bool>,
+    }
+
+    impl Recipient for MockRecipient {
+        async fn on_token_received(&self, to: Principal, notification: TokenReceived) -> Result<(), String> {
+            if self.reject.get() {
+                return Err("CanisterError: rejected".to_string());
+            }
+            self.received.borrow_mut().push((to, notification));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_call_guard() {
+        reset_state();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+
+        let guard = CallGuard::new(alice).unwrap();
+        assert!(matches!(CallGuard::new(alice), Err(TransferError::CallInProgress)));
+        assert!(CallGuard::new(bob).is_ok());
+        drop(guard);
+        assert!(CallGuard::new(alice).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_and_call() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let shop = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 100).is_ok());
+        let recipient = MockRecipient::default();
+
+        let result = block_on(transfer_and_call_with(&recipient, owner, shop, 30, vec![7])).unwrap();
+        assert_eq!(result.callback, CallbackStatus::Delivered);
+        assert_eq!(get_callback_status(result.block_index), Some(CallbackStatus::Delivered));
+        let (to, notification) = &recipient.received.borrow()[0];
+        assert_eq!((*to, notification.from, notification.amount), (shop, owner, 30));
+        assert_eq!((notification.block_index, notification.payload.clone()), (result.block_index, vec![7]));
+
+        // A rejected callback is recorded, and the transfer stands.
+        recipient.reject.set(true);
+        let result = block_on(transfer_and_call_with(&recipient, owner, shop, 20, Vec::new())).unwrap();
+        let failed = CallbackStatus::Failed { reason: "CanisterError: rejected".to_string() };
+        assert_eq!(result.callback, failed);
+        assert_eq!(get_callback_status(result.block_index), Some(failed));
+        assert_eq!((balance_of(&owner), balance_of(&shop)), (50, 50));
+
+        // A failed transfer makes no call.
+        assert!(matches!(
+            block_on(transfer_and_call_with(&recipient, owner, shop, 80, Vec::new())),
+            Err(TransferError::InsufficientBalance)
+        ));
+        assert_eq!(recipient.received.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,435 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{
+    change_fee, get_caller, get_time, is_owner, lock, mint_tokens, set_owner, set_paused, snapshots, voting_power, TransferError,
+};
+
+const MAX_PROPOSALS_PAGE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct GovernanceConfig {
+    // Minimum voting power needed to create a proposal.
+    proposal_threshold: u128,
+    // Locked from the proposer's balance until voting ends, so the number of open proposals is bounded by stake.
+    proposal_deposit: u128,
+    // Minimum total weight (for and against) for a vote to count.
+    quorum: u128,
+    voting_period: u64,
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            proposal_threshold: 1_000_000_000_000,
+            proposal_deposit: 1_000_000_000_000,
+            quorum: 10_000_000_000_000,
+            voting_period: 3 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum ProposalAction {
+    Mint { to: Principal, amount: u128 },
+    ChangeOwner { new_owner: Principal },
+    ChangeFee { fee: u128 },
+    // Stops or resumes transfers; see `transfers_paused`.
+    Pause { paused: bool },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum ProposalStatus {
+    Open,
+    Succeeded,
+    Defeated,
+    Executed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Proposal {
+    id: u64,
+    proposer: Principal,
+    description: String,
+    action: ProposalAction,
+    // Voting weights are delegated voting power at this snapshot.
+    snapshot_id: u64,
+    // Lock holding the proposer's deposit; released with `unlock` once voting ends.
+    deposit_lock_id: Option<u64>,
+    created_at: u64,
+    voting_ends_at: u64,
+    quorum: u128,
+    votes_for: u128,
+    votes_against: u128,
+    executed: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ProposalInfo {
+    proposal: Proposal,
+    status: ProposalStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Ballot {
+    support: bool,
+    weight: u128,
+}
+
+thread_local! {
+    static GOVERNANCE_CONFIG: RefCell<GovernanceConfig> = RefCell::new(GovernanceConfig::default());
+    static PROPOSALS: RefCell<Vec<Proposal>> = const { RefCell::new(Vec::new()) };
+    static BALLOTS: RefCell<HashMap<(u64, Principal), Ballot>> = RefCell::new(HashMap::new());
+}
+
+fn status_of(proposal: &Proposal, now: u64) -> ProposalStatus {
+    if proposal.executed {
+        ProposalStatus::Executed
+    } else if now < proposal.voting_ends_at {
+        ProposalStatus::Open
+    } else if proposal.votes_for.saturating_add(proposal.votes_against) >= proposal.quorum
+        && proposal.votes_for > proposal.votes_against
+    {
+        ProposalStatus::Succeeded
+    } else {
+        ProposalStatus::Defeated
+    }
+}
+
+fn with_proposal<R>(
+    proposal_id: u64,
+    f: impl FnOnce(&mut Proposal) -> Result<R, TransferError>,
+) -> Result<R, TransferError> {
+    PROPOSALS.with(|proposals| {
+        let mut proposals = proposals.borrow_mut();
+        let proposal = proposals.get_mut(proposal_id as usize).ok_or(TransferError::ProposalNotFound)?;
+        f(proposal)
+    })
+}
+
+#[update]
+fn set_governance_config(config: GovernanceConfig) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    GOVERNANCE_CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok(())
+}
+
+#[query]
+fn get_governance_config() -> GovernanceConfig {
+    GOVERNANCE_CONFIG.with(|config| config.borrow().clone())
+}
+
+#[update]
+fn create_proposal(description: String, action: ProposalAction) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    let config = get_governance_config();
+    if voting_power::current_votes(&caller) < config.proposal_threshold {
+        return Err(TransferError::BelowProposalThreshold);
+    }
+    let now = get_time();
+    let voting_ends_at = now.saturating_add(config.voting_period);
+    let deposit_lock_id = match config.proposal_deposit {
+        0 => None,
+        deposit => Some(lock(deposit, voting_ends_at)?),
+    };
+
+    let snapshot_id = snapshots::record_snapshot();
+    let id = PROPOSALS.with(|proposals| {
+        let mut proposals = proposals.borrow_mut();
+        let id = proposals.len() as u64;
+        proposals.push(Proposal {
+            id,
+            proposer: caller,
+            description,
+            action,
+            snapshot_id,
+            deposit_lock_id,
+            created_at: now,
+            voting_ends_at,
+            quorum: config.quorum,
+            votes_for: 0,
+            votes_against: 0,
+            executed: false,
+        });
+        id
+    });
+
+    ic_cdk::println!("Proposal {} created by {:?}", id, caller);
+    Ok(id)
+}
+
+#[update]
+fn vote(proposal_id: u64, support: bool) -> Result<u128, TransferError> {
+    let caller = get_caller();
+    if BALLOTS.with(|ballots| ballots.borrow().contains_key(&(proposal_id, caller))) {
+        return Err(TransferError::AlreadyVoted);
+    }
+
+    let weight = with_proposal(proposal_id, |proposal| {
+        if get_time() >= proposal.voting_ends_at {
+            return Err(TransferError::VotingClosed);
+        }
+        let weight = voting_power::votes_at_snapshot(&caller, proposal.snapshot_id);
+        if weight == 0 {
+            return Err(TransferError::NoVotingPower);
+        }
+        let tally = if support { &mut proposal.votes_for } else { &mut proposal.votes_against };
+        *tally = tally.checked_add(weight).ok_or(TransferError::OverflowError)?;
+        Ok(weight)
+    })?;
+    BALLOTS.with(|ballots| ballots.borrow_mut().insert((proposal_id, caller), Ballot { support, weight }));
+
+    Ok(weight)
+}
+
+#[update]
+fn execute_proposal(proposal_id: u64) -> Result<(), TransferError> {
+    let now = get_time();
+    let action = with_proposal(proposal_id, |proposal| {
+        match status_of(proposal, now) {
+            ProposalStatus::Open => Err(TransferError::VotingNotEnded),
+            ProposalStatus::Defeated => Err(TransferError::ProposalNotPassed),
+            ProposalStatus::Executed => Err(TransferError::ProposalAlreadyExecuted),
+            ProposalStatus::Succeeded => Ok(proposal.action.clone()),
+        }
+    })?;
+
+    match action {
+        ProposalAction::Mint { to, amount } => {
+            mint_tokens(to, amount)?;
+        }
+        ProposalAction::ChangeOwner { new_owner } => set_owner(new_owner),
+        ProposalAction::ChangeFee { fee } => change_fee(fee),
+        ProposalAction::Pause { paused } => set_paused(paused),
+    }
+    with_proposal(proposal_id, |proposal| {
+        proposal.executed = true;
+        Ok(())
+    })?;
+
+    ic_cdk::println!("Proposal {} executed", proposal_id);
+    Ok(())
+}
+
+#[query]
+fn get_proposal(proposal_id: u64) -> Option<ProposalInfo> {
+    let now = get_time();
+    PROPOSALS.with(|proposals| {
+        proposals.borrow().get(proposal_id as usize).map(|proposal| ProposalInfo {
+            proposal: proposal.clone(),
+            status: status_of(proposal, now),
+        })
+    })
+}
+
+#[query]
+fn list_proposals(start: u64, limit: u32) -> Vec<ProposalInfo> {
+    let now = get_time();
+    PROPOSALS.with(|proposals| {
+        proposals.borrow()
+            .iter()
+            .skip(start as usize)
+            .take((limit as usize).min(MAX_PROPOSALS_PAGE))
+            .map(|proposal| ProposalInfo {
+                proposal: proposal.clone(),
+                status: status_of(proposal, now),
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_ballot(proposal_id: u64, voter: Principal) -> Option<Ballot> {
+    BALLOTS.with(|ballots| ballots.borrow().get(&(proposal_id, voter)).cloned())
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        GOVERNANCE_CONFIG.with(|config| config.take()),
+        PROPOSALS.with(|proposals| proposals.take()),
+        BALLOTS.with(|ballots| ballots.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (config, proposals, ballots) = candid::decode_one(bytes)?;
+    GOVERNANCE_CONFIG.with(|state| *state.borrow_mut() = config);
+    PROPOSALS.with(|state| *state.borrow_mut() = proposals);
+    BALLOTS.with(|state| *state.borrow_mut() = ballots);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    GOVERNANCE_CONFIG.with(|config| *config.borrow_mut() = GovernanceConfig::default());
+    PROPOSALS.with(|proposals| proposals.borrow_mut().clear());
+    BALLOTS.with(|ballots| ballots.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balance_of, get_balance, get_token_info, mint, reset_state, test_utils, transfer, transfers_paused, unlock, voting_power,
+        OWNER,
+    };
+
+    fn setup() -> (Principal, Principal, Principal) {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(owner);
+        assert!(set_governance_config(GovernanceConfig {
+            proposal_threshold: 100,
+            proposal_deposit: 100,
+            quorum: 500,
+            voting_period: 100,
+        }).is_ok());
+        assert!(mint(alice, 600).is_ok());
+        assert!(mint(bob, 50).is_ok());
+        (owner, alice, bob)
+    }
+
+    #[test]
+    fn test_proposal_lifecycle() {
+        let (owner, alice, bob) = setup();
+        let new_owner = Principal::from_slice(&[7]);
+
+        test_utils::set_caller(bob);
+        assert!(matches!(
+            create_proposal("too small".to_string(), ProposalAction::ChangeOwner { new_owner }),
+            Err(TransferError::BelowProposalThreshold)
+        ));
+
+        test_utils::set_caller(alice);
+        let id = create_proposal("hand over".to_string(), ProposalAction::ChangeOwner { new_owner }).unwrap();
+
+        // Tokens moved after creation do not add voting weight.
+        assert!(transfer(bob, 100).is_ok());
+        assert_eq!(vote(id, true).unwrap(), 600);
+        assert!(matches!(vote(id, true), Err(TransferError::AlreadyVoted)));
+        test_utils::set_caller(bob);
+        assert_eq!(vote(id, false).unwrap(), 50);
+        test_utils::set_caller(owner);
+        assert!(matches!(vote(id, true), Err(TransferError::NoVotingPower)));
+
+        assert!(matches!(execute_proposal(id), Err(TransferError::VotingNotEnded)));
+        test_utils::set_time(100);
+        assert!(matches!(vote(id, true), Err(TransferError::VotingClosed)));
+
+        let info = get_proposal(id).unwrap();
+        assert_eq!(info.status, ProposalStatus::Succeeded);
+        assert_eq!((info.proposal.votes_for, info.proposal.votes_against), (600, 50));
+        assert_eq!(get_ballot(id, bob), Some(Ballot { support: false, weight: 50 }));
+
+        assert!(execute_proposal(id).is_ok());
+        OWNER.with(|o| assert_eq!(*o.borrow(), new_owner));
+        assert!(matches!(execute_proposal(id), Err(TransferError::ProposalAlreadyExecuted)));
+    }
+
+    #[test]
+    fn test_proposal_without_quorum_is_defeated() {
+        let (_, alice, bob) = setup();
+
+        test_utils::set_caller(alice);
+        let id = create_proposal("mint".to_string(), ProposalAction::Mint { to: alice, amount: 1000 }).unwrap();
+        test_utils::set_caller(bob);
+        assert!(vote(id, true).is_ok());
+
+        test_utils::set_time(100);
+        assert_eq!(get_proposal(id).unwrap().status, ProposalStatus::Defeated);
+        assert!(matches!(execute_proposal(id), Err(TransferError::ProposalNotPassed)));
+        assert_eq!(balance_of(&alice), 600);
+        assert_eq!(list_proposals(0, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_votes_are_weighted_by_delegated_power() {
+        let (_, alice, bob) = setup();
+        let carol = Principal::from_slice(&[7]);
+
+        test_utils::set_caller(bob);
+        assert!(voting_power::delegate(alice).is_ok());
+        test_utils::set_caller(alice);
+        let id = create_proposal("mint".to_string(), ProposalAction::Mint { to: carol, amount: 1 }).unwrap();
+
+        // Delegation after the snapshot does not move weight.
+        test_utils::set_caller(bob);
+        assert!(voting_power::delegate(bob).is_ok());
+        assert!(matches!(vote(id, false), Err(TransferError::NoVotingPower)));
+        test_utils::set_caller(alice);
+        assert_eq!(vote(id, true).unwrap(), 650);
+    }
+
+    // Alice alone passes `action`; voting ends at `ends_at`.
+    fn pass(action: ProposalAction, ends_at: u64) -> u64 {
+        test_utils::set_caller(Principal::from_text("aaaaa-aa").unwrap());
+        let id = create_proposal("action".to_string(), action).unwrap();
+        assert!(vote(id, true).is_ok());
+        test_utils::set_time(ends_at);
+        id
+    }
+
+    #[test]
+    fn test_fee_and_pause_actions() {
+        let (_, alice, bob) = setup();
+        assert!(set_governance_config(GovernanceConfig { quorum: 100, ..get_governance_config() }).is_ok());
+
+        let id = pass(ProposalAction::ChangeFee { fee: 10 }, 100);
+        assert!(execute_proposal(id).is_ok());
+        assert_eq!(get_token_info().fee, 10);
+        assert!(transfer(bob, 100).is_ok());
+        assert_eq!(balance_of(&alice), 490);
+
+        let id = pass(ProposalAction::Pause { paused: true }, 200);
+        assert!(execute_proposal(id).is_ok());
+        assert!(transfers_paused());
+        assert!(matches!(transfer(bob, 100), Err(TransferError::TransfersPaused)));
+        assert!(matches!(crate::move_balance(alice, bob, 100), Err(TransferError::TransfersPaused)));
+
+        let id = pass(ProposalAction::Pause { paused: false }, 300);
+        assert!(execute_proposal(id).is_ok());
+        assert!(transfer(bob, 100).is_ok());
+        assert_eq!(balance_of(&bob), 250);
+    }
+
+    #[test]
+    fn test_tally_saturates() {
+        let (_, alice, _) = setup();
+        test_utils::set_caller(alice);
+        let id = create_proposal("mint".to_string(), ProposalAction::Mint { to: alice, amount: 1 }).unwrap();
+        with_proposal(id, |proposal| {
+            proposal.votes_for = u128::MAX;
+            proposal.votes_against = 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(get_proposal(id).unwrap().status, ProposalStatus::Open);
+        test_utils::set_time(100);
+        assert_eq!(get_proposal(id).unwrap().status, ProposalStatus::Succeeded);
+    }
+
+    #[test]
+    fn test_proposal_deposit_limits_open_proposals() {
+        let (_, alice, _) = setup();
+        let action = || ProposalAction::ChangeOwner { new_owner: alice };
+
+        test_utils::set_caller(alice);
+        for _ in 0..6 {
+            assert!(create_proposal("spam".to_string(), action()).is_ok());
+        }
+        assert!(matches!(create_proposal("spam".to_string(), action()), Err(TransferError::InsufficientBalance)));
+        assert_eq!(get_balance(alice).locked, 600);
+
+        test_utils::set_time(100);
+        let lock_id = get_proposal(0).unwrap().proposal.deposit_lock_id.unwrap();
+        assert_eq!(unlock(lock_id).unwrap(), 100);
+        assert!(create_proposal("again".to_string(), action()).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{charge_fee, ensure_covers_fee, get_caller, get_time, move_balance, push_event, Operation, TransferError, TransferEvent, DEFAULT_TOKEN, TRANSFER_EVENTS};
+
+thread_local! {
+    // Total refunded so far per original block index.
+    static REFUNDED: RefCell<HashMap<u64, u128>> = RefCell::new(HashMap::new());
+}
+
+// Pays back up to the amount of an ICPT transfer the caller received. Partial refunds are allowed as long as
+// their total stays within the original payment. Returns the block index of the refund.
+#[update]
+fn refund(tx_index: u64, amount: u128) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    let original = TRANSFER_EVENTS.with(|events| events.borrow().get(tx_index as usize).cloned())
+        .ok_or(TransferError::TransactionNotFound)?;
+    if original.operation != Operation::Transfer || original.token != DEFAULT_TOKEN {
+        return Err(TransferError::NotRefundable);
+    }
+    if original.to != caller {
+        return Err(TransferError::Unauthorized);
+    }
+    let refunded = get_refunded(tx_index);
+    let total = refunded.checked_add(amount).ok_or(TransferError::OverflowError)?;
+    if total > original.amount {
+        return Err(TransferError::RefundExceedsPayment);
+    }
+
+    ensure_covers_fee(&caller, amount)?;
+    move_balance(caller, original.from, amount)?;
+    REFUNDED.with(|refunds| refunds.borrow_mut().insert(tx_index, total));
+    let block_index = push_event(TransferEvent {
+        token: DEFAULT_TOKEN.to_string(),
+        operation: Operation::Refund,
+        from: caller,
+        to: original.from,
+        amount,
+        timestamp: get_time(),
+        batch_id: None,
+        refund_of: Some(tx_index),
+    });
+    charge_fee(caller)?;
+    Ok(block_index)
+}
+
+#[query]
+fn get_refunded(tx_index: u64) -> u128 {
+    REFUNDED.with(|refunds| refunds.borrow().get(&tx_index).cloned().unwrap_or(0))
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(REFUNDED.with(|refunded| refunded.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let refunded = candid::decode_one(bytes)?;
+    REFUNDED.with(|state| *state.borrow_mut() = refunded);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    REFUNDED.with(|refunds| refunds.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use crate::{balance_of, get_transfer_history, mint, reset_state, test_utils, transfer_from, OWNER};
+
+    #[test]
+    fn test_refund_capped_at_payment() {
+        reset_state();
+        let payer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let merchant = Principal::from_text("aaaaa-aa").unwrap();
+        let other = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = payer);
+        test_utils::set_caller(payer);
+        assert!(mint(payer, 1000).is_ok());
+        assert!(mint(other, 1000).is_ok());
+        let payment = transfer_from(payer, merchant, 300).unwrap();
+
+        test_utils::set_caller(other);
+        assert!(matches!(refund(payment, 100), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(merchant);
+        assert!(matches!(refund(0, 100), Err(TransferError::NotRefundable)));
+        assert!(matches!(refund(99, 100), Err(TransferError::TransactionNotFound)));
+
+        let first = refund(payment, 100).unwrap();
+        assert!(refund(payment, 150).is_ok());
+        assert!(matches!(refund(payment, 51), Err(TransferError::RefundExceedsPayment)));
+        assert!(refund(payment, 50).is_ok());
+        assert_eq!(get_refunded(payment), 300);
+
+        assert_eq!((balance_of(&payer), balance_of(&merchant)), (1000, 0));
+        let event = &get_transfer_history()[first as usize];
+        assert_eq!((event.operation.clone(), event.from, event.to), (Operation::Refund, merchant, payer));
+        assert_eq!(event.refund_of, Some(payment));
+        // Refunds themselves cannot be refunded.
+        test_utils::set_caller(payer);
+        assert!(matches!(refund(first, 100), Err(TransferError::NotRefundable)));
+    }
+
+    #[test]
+    fn test_refund_pays_fee() {
+        reset_state();
+        let payer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let merchant = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = payer);
+        test_utils::set_caller(payer);
+        assert!(mint(payer, 1000).is_ok());
+        assert!(mint(merchant, 5).is_ok());
+        assert!(crate::set_transfer_fee(10).is_ok());
+        let payment = transfer_from(payer, merchant, 300).unwrap();
+
+        // The merchant pays the fee of the refund on top of the refunded amount.
+        test_utils::set_caller(merchant);
+        assert!(matches!(refund(payment, 300), Err(TransferError::InsufficientBalance)));
+        assert!(refund(payment, 295).is_ok());
+        assert_eq!((balance_of(&payer), balance_of(&merchant)), (985, 0));
+    }
+}
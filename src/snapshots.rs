@@ -0,0 +1,224 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound::{Excluded, Unbounded};
+
+use crate::{balance_of, get_time, is_owner, TransferError, TRANSFER_EVENTS};
+
+const MAX_HOLDERS_PAGE: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Snapshot {
+    id: u64,
+    timestamp: u64,
+    // Number of events in the transfer history when the snapshot was taken.
+    block_index: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct SnapshotHolder {
+    owner: Principal,
+    balance: u128,
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<Vec<Snapshot>> = const { RefCell::new(Vec::new()) };
+    // Per account, `(snapshot_id, balance)` pairs in increasing id order. An entry records the balance the account
+    // held before its first change after snapshot `snapshot_id` was taken.
+    static CHECKPOINTS: RefCell<HashMap<Principal, Vec<(u64, u128)>>> = RefCell::new(HashMap::new());
+    // Every account that has ever been credited ICPT, in principal order. Accounts stay in the index after their
+    // balance drops to zero, since older snapshots may still list them.
+    static HOLDERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Called after every ICPT credit.
+pub(crate) fn record_holder(owner: Principal) {
+    HOLDERS.with(|holders| holders.borrow_mut().insert(owner));
+}
+
+pub(crate) fn current_snapshot_id() -> u64 {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().len() as u64)
+}
+
+// Called before every balance change; stores the old balance once per account and snapshot.
+pub(crate) fn checkpoint(owner: Principal) {
+    let current = current_snapshot_id();
+    if current == 0 {
+        return;
+    }
+    CHECKPOINTS.with(|checkpoints| {
+        let mut checkpoints = checkpoints.borrow_mut();
+        let entries = checkpoints.entry(owner).or_default();
+        if entries.last().is_none_or(|(id, _)| *id < current) {
+            entries.push((current, balance_of(&owner)));
+        }
+    });
+}
+
+pub(crate) fn balance_at(owner: &Principal, snapshot_id: u64) -> u128 {
+    let recorded = CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow().get(owner).and_then(|entries| {
+            let index = entries.partition_point(|(id, _)| *id < snapshot_id);
+            entries.get(index).map(|(_, balance)| *balance)
+        })
+    });
+    // No change since the snapshot was taken, so the current balance is the historical one.
+    recorded.unwrap_or_else(|| balance_of(owner))
+}
+
+fn ensure_snapshot_exists(snapshot_id: u64) -> Result<(), TransferError> {
+    if snapshot_id == 0 || snapshot_id > current_snapshot_id() {
+        return Err(TransferError::SnapshotNotFound);
+    }
+    Ok(())
+}
+
+#[update]
+fn take_snapshot() -> Result<u64, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    Ok(record_snapshot())
+}
+
+// Also used by governance to fix voting weights when a proposal is created.
+pub(crate) fn record_snapshot() -> u64 {
+    let block_index = TRANSFER_EVENTS.with(|events| events.borrow().len() as u64);
+    let id = SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        let id = snapshots.len() as u64 + 1;
+        snapshots.push(Snapshot {
+            id,
+            timestamp: get_time(),
+            block_index,
+        });
+        id
+    });
+
+    ic_cdk::println!("Snapshot {} taken at block {}", id, block_index);
+    id
+}
+
+#[query]
+fn get_snapshot(snapshot_id: u64) -> Option<Snapshot> {
+    SNAPSHOTS.with(|snapshots| {
+        snapshot_id.checked_sub(1)
+            .and_then(|index| snapshots.borrow().get(index as usize).cloned())
+    })
+}
+
+#[query]
+fn balance_of_at(owner: Principal, snapshot_id: u64) -> Result<u128, TransferError> {
+    ensure_snapshot_exists(snapshot_id)?;
+    Ok(balance_at(&owner, snapshot_id))
+}
+
+// Holders with a non-zero balance at the snapshot, ordered by principal. Pass the last owner of a page as
+// `start_after` to fetch the next one.
+#[query]
+fn get_snapshot_holders(snapshot_id: u64, start_after: Option<Principal>, limit: u32) -> Result<Vec<SnapshotHolder>, TransferError> {
+    ensure_snapshot_exists(snapshot_id)?;
+
+    let start = start_after.map_or(Unbounded, Excluded);
+    Ok(HOLDERS.with(|holders| {
+        holders.borrow()
+            .range((start, Unbounded))
+            .map(|owner| SnapshotHolder {
+                owner: *owner,
+                balance: balance_at(owner, snapshot_id),
+            })
+            .filter(|holder| holder.balance > 0)
+            .take((limit as usize).min(MAX_HOLDERS_PAGE))
+            .collect()
+    }))
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        SNAPSHOTS.with(|snapshots| snapshots.take()),
+        CHECKPOINTS.with(|checkpoints| checkpoints.take()),
+        HOLDERS.with(|holders| holders.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (snapshots, checkpoints, holders) = candid::decode_one(bytes)?;
+    SNAPSHOTS.with(|state| *state.borrow_mut() = snapshots);
+    CHECKPOINTS.with(|state| *state.borrow_mut() = checkpoints);
+    HOLDERS.with(|state| *state.borrow_mut() = holders);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().clear());
+    CHECKPOINTS.with(|checkpoints| checkpoints.borrow_mut().clear());
+    HOLDERS.with(|holders| holders.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{burn, mint, reset_state, test_utils, transfer, OWNER};
+
+    #[test]
+    fn test_balance_of_at() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(owner);
+        assert!(mint(alice, 1000).is_ok());
+        let first = take_snapshot().unwrap();
+
+        test_utils::set_caller(alice);
+        assert!(matches!(take_snapshot(), Err(TransferError::Unauthorized)));
+        assert!(transfer(bob, 300).is_ok());
+        assert!(transfer(bob, 100).is_ok());
+
+        test_utils::set_caller(owner);
+        let second = take_snapshot().unwrap();
+        let third = take_snapshot().unwrap();
+
+        test_utils::set_caller(bob);
+        assert!(burn(400).is_ok());
+
+        assert_eq!(balance_of_at(alice, first).unwrap(), 1000);
+        assert_eq!(balance_of_at(bob, first).unwrap(), 0);
+        assert_eq!(balance_of_at(alice, second).unwrap(), 600);
+        assert_eq!(balance_of_at(bob, second).unwrap(), 400);
+        assert_eq!(balance_of_at(bob, third).unwrap(), 400);
+        assert_eq!(balance_of(&bob), 0);
+        assert!(matches!(balance_of_at(bob, 0), Err(TransferError::SnapshotNotFound)));
+        assert!(matches!(balance_of_at(bob, 4), Err(TransferError::SnapshotNotFound)));
+        assert_eq!(get_snapshot(second).unwrap().block_index, 3);
+    }
+
+    #[test]
+    fn test_snapshot_holders_pagination() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        test_utils::set_caller(owner);
+
+        let holders: Vec<Principal> = (1..=5u8).map(|i| Principal::from_slice(&[i])).collect();
+        for holder in &holders {
+            assert!(mint(*holder, 10).is_ok());
+        }
+        let snapshot = take_snapshot().unwrap();
+
+        test_utils::set_caller(holders[0]);
+        assert!(burn(10).is_ok());
+        test_utils::set_caller(owner);
+        assert!(mint(owner, 10).is_ok());
+
+        let page = get_snapshot_holders(snapshot, None, 2).unwrap();
+        assert_eq!(page.iter().map(|h| h.owner).collect::<Vec<_>>(), holders[..2].to_vec());
+        let page = get_snapshot_holders(snapshot, Some(page[1].owner), 10).unwrap();
+        assert_eq!(page.iter().map(|h| h.owner).collect::<Vec<_>>(), holders[2..].to_vec());
+        assert!(page.iter().all(|h| h.balance == 10));
+    }
+}
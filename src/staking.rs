@@ -0,0 +1,439 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{credit_balance, debit_balance, get_caller, get_time, is_owner, record_event, Operation, TransferError, TOKEN};
+
+// Reward-per-share values are scaled by this factor to keep precision in integer math.
+const REWARD_PRECISION: u128 = 1_000_000_000_000;
+const UNSTAKE_COOLDOWN_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct PendingUnstake {
+    amount: u128,
+    available_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct StakeInfo {
+    staked: u128,
+    // Rewards already accounted for at the current `reward_per_share`.
+    reward_debt: u128,
+    pending_rewards: u128,
+    unstaking: Vec<PendingUnstake>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct StakingInfo {
+    total_staked: u128,
+    reward_per_share: u128,
+    unstake_cooldown: u64,
+    undistributed_fees: u128,
+}
+
+thread_local! {
+    static STAKES: RefCell<HashMap<Principal, StakeInfo>> = RefCell::new(HashMap::new());
+    static TOTAL_STAKED: RefCell<u128> = const { RefCell::new(0) };
+    static REWARD_PER_SHARE: RefCell<u128> = const { RefCell::new(0) };
+    // Transfer fees not shared out yet: collected while nothing was staked, or lost to rounding.
+    static UNDISTRIBUTED_FEES: RefCell<u128> = const { RefCell::new(0) };
+}
+
+fn reward_per_share() -> u128 {
+    REWARD_PER_SHARE.with(|acc| *acc.borrow())
+}
+
+// `a * b / divisor` with a 256-bit intermediate product; `None` only if the result does not fit in a u128.
+fn mul_div(a: u128, b: u128, divisor: u128) -> Option<u128> {
+    const LOW: u128 = u64::MAX as u128;
+    let (a_high, a_low, b_high, b_low) = (a >> 64, a & LOW, b >> 64, b & LOW);
+    let (low_low, low_high, high_low) = (a_low * b_low, a_low * b_high, a_high * b_low);
+    let middle = (low_low >> 64) + (low_high & LOW) + (high_low & LOW);
+    let low = (low_low & LOW) | (middle << 64);
+    let high = a_high * b_high + (low_high >> 64) + (high_low >> 64) + (middle >> 64);
+    if divisor == 0 || high >= divisor {
+        return None;
+    }
+
+    // Long division of `high:low`, one bit at a time; `remainder` stays below `divisor`.
+    let (mut remainder, mut quotient) = (high, 0u128);
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= divisor {
+            remainder = remainder.wrapping_sub(divisor);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
+
+fn accrued_rewards(staked: u128, reward_per_share: u128) -> Result<u128, TransferError> {
+    mul_div(staked, reward_per_share, REWARD_PRECISION).ok_or(TransferError::OverflowError)
+}
+
+// Moves everything earned since the last stake change into `pending_rewards`.
+fn settle(stake: &mut StakeInfo, reward_per_share: u128) -> Result<(), TransferError> {
+    let accrued = accrued_rewards(stake.staked, reward_per_share)?;
+    stake.pending_rewards = stake.pending_rewards
+        .checked_add(accrued.saturating_sub(stake.reward_debt))
+        .ok_or(TransferError::OverflowError)?;
+    stake.reward_debt = accrued;
+    Ok(())
+}
+
+// Spreads `amount` over the `total_staked` units; returns the new reward per share and what the stakers can
+// actually claim between them after rounding.
+fn share(amount: u128, total_staked: u128) -> Result<(u128, u128), TransferError> {
+    let increment = mul_div(amount, REWARD_PRECISION, total_staked).ok_or(TransferError::OverflowError)?;
+    let new_acc = reward_per_share().checked_add(increment).ok_or(TransferError::OverflowError)?;
+    let distributed = mul_div(increment, total_staked, REWARD_PRECISION).ok_or(TransferError::OverflowError)?;
+    Ok((new_acc, distributed))
+}
+
+// Hands the undistributed fees to current stakers, if there are any.
+fn share_fees() {
+    let total_staked = TOTAL_STAKED.with(|total| *total.borrow());
+    let fees = UNDISTRIBUTED_FEES.with(|fees| *fees.borrow());
+    if total_staked == 0 || fees == 0 {
+        return;
+    }
+    // On overflow the fees stay pooled; a later stake change spreads them over more units.
+    if let Ok((new_acc, distributed)) = share(fees, total_staked) {
+        REWARD_PER_SHARE.with(|acc| *acc.borrow_mut() = new_acc);
+        UNDISTRIBUTED_FEES.with(|fees| *fees.borrow_mut() -= distributed);
+    }
+}
+
+// Adds a transfer fee, already taken out of the supply, to the staking rewards. Like other rewards it is minted
+// again when claimed.
+pub(crate) fn add_fee_reward(fee: u128) {
+    UNDISTRIBUTED_FEES.with(|fees| {
+        let mut fees = fees.borrow_mut();
+        *fees = fees.saturating_add(fee);
+    });
+    share_fees();
+}
+
+#[update]
+fn stake(amount: u128) -> Result<bool, TransferError> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    let total_staked = TOTAL_STAKED.with(|total| *total.borrow())
+        .checked_add(amount)
+        .ok_or(TransferError::OverflowError)?;
+    debit_balance(caller, amount)?;
+    let acc = reward_per_share();
+    STAKES.with(|stakes| {
+        let mut stakes = stakes.borrow_mut();
+        let stake = stakes.entry(caller).or_default();
+        settle(stake, acc)?;
+        stake.staked = stake.staked.checked_add(amount).ok_or(TransferError::OverflowError)?;
+        stake.reward_debt = accrued_rewards(stake.staked, acc)?;
+        Ok::<(), TransferError>(())
+    })?;
+    TOTAL_STAKED.with(|total| *total.borrow_mut() = total_staked);
+    share_fees();
+
+    record_event(Operation::Stake, caller, caller, amount);
+    Ok(true)
+}
+
+#[update]
+fn unstake(amount: u128) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    let acc = reward_per_share();
+    let available_at = get_time().saturating_add(UNSTAKE_COOLDOWN_NS);
+    STAKES.with(|stakes| {
+        let mut stakes = stakes.borrow_mut();
+        let stake = stakes.get_mut(&caller).ok_or(TransferError::NothingStaked)?;
+        if stake.staked < amount {
+            return Err(TransferError::InsufficientBalance);
+        }
+        settle(stake, acc)?;
+        stake.staked -= amount;
+        stake.reward_debt = accrued_rewards(stake.staked, acc)?;
+        stake.unstaking.push(PendingUnstake { amount, available_at });
+        Ok(())
+    })?;
+    // Every stake is part of the total, so this cannot underflow.
+    TOTAL_STAKED.with(|total| {
+        let mut total = total.borrow_mut();
+        *total = total.saturating_sub(amount);
+    });
+
+    record_event(Operation::Unstake, caller, caller, amount);
+    Ok(available_at)
+}
+
+#[update]
+fn withdraw_unstaked() -> Result<u128, TransferError> {
+    let caller = get_caller();
+    let now = get_time();
+
+    let amount = STAKES.with(|stakes| {
+        let mut stakes = stakes.borrow_mut();
+        let stake = stakes.get_mut(&caller).ok_or(TransferError::NothingStaked)?;
+        if stake.unstaking.is_empty() {
+            return Err(TransferError::NothingStaked);
+        }
+        let (ready, waiting): (Vec<_>, Vec<_>) = stake.unstaking.drain(..)
+            .partition(|pending| pending.available_at <= now);
+        stake.unstaking = waiting;
+        if ready.is_empty() {
+            return Err(TransferError::CooldownNotElapsed);
+        }
+        Ok(ready.iter().map(|pending| pending.amount).sum::<u128>())
+    })?;
+
+    credit_balance(caller, amount)?;
+    record_event(Operation::WithdrawUnstaked, caller, caller, amount);
+    Ok(amount)
+}
+
+#[update]
+fn claim_rewards() -> Result<u128, TransferError> {
+    let caller = get_caller();
+    let acc = reward_per_share();
+
+    let amount = STAKES.with(|stakes| {
+        let mut stakes = stakes.borrow_mut();
+        let stake = stakes.get_mut(&caller).ok_or(TransferError::NothingStaked)?;
+        settle(stake, acc)?;
+        Ok::<u128, TransferError>(std::mem::take(&mut stake.pending_rewards))
+    })?;
+
+    if amount > 0 {
+        TOKEN.with(|token| {
+            let mut token = token.borrow_mut();
+            token.total_supply = token.total_supply.checked_add(amount).ok_or(TransferError::OverflowError)?;
+            Ok::<(), TransferError>(())
+        })?;
+        credit_balance(caller, amount)?;
+        record_event(Operation::ClaimRewards, caller, caller, amount);
+    }
+    Ok(amount)
+}
+
+// Shares `amount` new tokens pro rata among current stakers. Rewards are minted when claimed, so the fractions
+// lost to integer division never enter the supply.
+#[update]
+fn distribute_rewards(amount: u128) -> Result<bool, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    let total_staked = TOTAL_STAKED.with(|total| *total.borrow());
+    if total_staked == 0 {
+        return Err(TransferError::NothingStaked);
+    }
+
+    let (new_acc, distributed) = share(amount, total_staked)?;
+    if distributed == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    REWARD_PER_SHARE.with(|acc| *acc.borrow_mut() = new_acc);
+
+    let caller = get_caller();
+    record_event(Operation::DistributeRewards, caller, caller, distributed);
+    Ok(true)
+}
+
+#[query]
+fn get_stake(owner: Principal) -> StakeInfo {
+    let acc = reward_per_share();
+    STAKES.with(|stakes| {
+        let mut stake = stakes.borrow().get(&owner).cloned().unwrap_or_default();
+        // Overflow here would already have failed the last update, so the stored view is good enough.
+        let _ = settle(&mut stake, acc);
+        stake
+    })
+}
+
+#[query]
+fn get_staking_info() -> StakingInfo {
+    StakingInfo {
+        total_staked: TOTAL_STAKED.with(|total| *total.borrow()),
+        reward_per_share: reward_per_share(),
+        unstake_cooldown: UNSTAKE_COOLDOWN_NS,
+        undistributed_fees: UNDISTRIBUTED_FEES.with(|fees| *fees.borrow()),
+    }
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        STAKES.with(|stakes| stakes.take()),
+        TOTAL_STAKED.with(|total| total.take()),
+        REWARD_PER_SHARE.with(|reward_per_share| reward_per_share.take()),
+        UNDISTRIBUTED_FEES.with(|fees| fees.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (stakes, total, reward_per_share, fees) = candid::decode_one(bytes)?;
+    STAKES.with(|state| *state.borrow_mut() = stakes);
+    TOTAL_STAKED.with(|state| *state.borrow_mut() = total);
+    REWARD_PER_SHARE.with(|state| *state.borrow_mut() = reward_per_share);
+    UNDISTRIBUTED_FEES.with(|state| *state.borrow_mut() = fees);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    STAKES.with(|stakes| stakes.borrow_mut().clear());
+    TOTAL_STAKED.with(|total| *total.borrow_mut() = 0);
+    REWARD_PER_SHARE.with(|acc| *acc.borrow_mut() = 0);
+    UNDISTRIBUTED_FEES.with(|fees| *fees.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, get_transfer_history, mint, reset_state, set_transfer_fee, test_utils, transfer, OWNER};
+
+    fn setup() -> (Principal, Principal, Principal) {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(owner);
+        assert!(mint(alice, 1000).is_ok());
+        assert!(mint(bob, 1000).is_ok());
+        (owner, alice, bob)
+    }
+
+    #[test]
+    fn test_rewards_split_by_stake() {
+        let (owner, alice, bob) = setup();
+
+        test_utils::set_caller(alice);
+        assert!(stake(300).is_ok());
+        test_utils::set_caller(bob);
+        assert!(stake(100).is_ok());
+        assert_eq!(balance_of(&alice), 700);
+
+        test_utils::set_caller(alice);
+        assert!(matches!(distribute_rewards(400), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(owner);
+        assert!(distribute_rewards(400).is_ok());
+
+        // Bob joins late and must not share in rewards distributed before his stake.
+        test_utils::set_caller(bob);
+        assert!(stake(200).is_ok());
+        test_utils::set_caller(owner);
+        assert!(distribute_rewards(600).is_ok());
+
+        test_utils::set_caller(alice);
+        assert_eq!(claim_rewards().unwrap(), 300 + 300);
+        assert_eq!(claim_rewards().unwrap(), 0);
+        test_utils::set_caller(bob);
+        assert_eq!(claim_rewards().unwrap(), 100 + 300);
+
+        assert_eq!(balance_of(&alice), 1300);
+        TOKEN.with(|token| assert_eq!(token.borrow().total_supply, 1_000_000_000_000_003_000));
+        assert_eq!(get_staking_info().total_staked, 600);
+    }
+
+    #[test]
+    fn test_unstake_cooldown() {
+        let (_, alice, _) = setup();
+
+        test_utils::set_caller(alice);
+        assert!(matches!(stake(2000), Err(TransferError::InsufficientBalance)));
+        assert!(stake(500).is_ok());
+        assert!(matches!(unstake(600), Err(TransferError::InsufficientBalance)));
+
+        test_utils::set_time(10);
+        assert_eq!(unstake(200).unwrap(), 10 + UNSTAKE_COOLDOWN_NS);
+        assert_eq!(get_stake(alice).staked, 300);
+        assert!(matches!(withdraw_unstaked(), Err(TransferError::CooldownNotElapsed)));
+
+        test_utils::set_time(10 + UNSTAKE_COOLDOWN_NS);
+        assert_eq!(withdraw_unstaked().unwrap(), 200);
+        assert_eq!(balance_of(&alice), 700);
+
+        let operations: Vec<Operation> = get_transfer_history().into_iter().map(|event| event.operation).skip(2).collect();
+        assert_eq!(operations, vec![Operation::Stake, Operation::Unstake, Operation::WithdrawUnstaked]);
+    }
+
+    #[test]
+    fn test_rounding_and_large_rewards() {
+        let (owner, alice, _) = setup();
+        let supply = || TOKEN.with(|token| token.borrow().total_supply);
+        let initial_supply = supply();
+
+        // 10 shared over 3 staked leaves each staked unit 3.33..., of which only 9 whole units are claimable.
+        test_utils::set_caller(alice);
+        assert!(stake(3).is_ok());
+        test_utils::set_caller(owner);
+        assert!(distribute_rewards(10).is_ok());
+        assert_eq!(supply(), initial_supply);
+        test_utils::set_caller(alice);
+        assert_eq!(claim_rewards().unwrap(), 9);
+        assert_eq!(supply(), initial_supply + 9);
+
+        // A large reward per share must not overflow later stake changes.
+        assert!(unstake(2).is_ok());
+        test_utils::set_caller(owner);
+        assert!(distribute_rewards(10u128.pow(25)).is_ok());
+        assert!(matches!(distribute_rewards(10u128.pow(30)), Err(TransferError::OverflowError)));
+        test_utils::set_caller(alice);
+        assert!(stake(100).is_ok());
+        assert!(unstake(50).is_ok());
+        assert_eq!(claim_rewards().unwrap(), 10u128.pow(25));
+        assert_eq!(get_staking_info().total_staked, 51);
+    }
+
+    #[test]
+    fn test_transfer_fees_go_to_stakers() {
+        let (owner, alice, bob) = setup();
+        let supply = || TOKEN.with(|token| token.borrow().total_supply);
+        test_utils::set_caller(owner);
+        assert!(set_transfer_fee(10).is_ok());
+
+        // A fee paid while nothing is staked waits for the first staker.
+        test_utils::set_caller(bob);
+        assert!(transfer(alice, 100).is_ok());
+        assert_eq!(get_staking_info().undistributed_fees, 10);
+        test_utils::set_caller(alice);
+        assert!(stake(200).is_ok());
+        assert_eq!(get_staking_info().undistributed_fees, 0);
+
+        // Fees leave the supply when paid and return when claimed.
+        test_utils::set_caller(bob);
+        assert!(stake(200).is_ok());
+        let initial_supply = supply();
+        for _ in 0..4 {
+            assert!(transfer(owner, 50).is_ok());
+        }
+        assert_eq!(supply(), initial_supply - 40);
+        test_utils::set_caller(alice);
+        assert_eq!(claim_rewards().unwrap(), 10 + 20);
+        test_utils::set_caller(bob);
+        assert_eq!(claim_rewards().unwrap(), 20);
+        assert_eq!(supply(), initial_supply + 10);
+    }
+
+    #[test]
+    fn test_mul_div() {
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), Some(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 3, 4), Some(u128::MAX / 4 * 3 + 2));
+        assert_eq!(mul_div(1 << 100, 1 << 100, 1 << 80), Some(1 << 120));
+        assert_eq!(mul_div(1 << 100, 1 << 100, 1 << 70), None);
+        assert_eq!(mul_div(7, 3, 2), Some(10));
+        assert_eq!(mul_div(1, 1, 0), None);
+    }
+}
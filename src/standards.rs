@@ -0,0 +1,138 @@
+// Discovery for tools: the standards this canister implements (ICRC-10) and the token's ICRC-1 metadata.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+
+use crate::{batch, consent, get_token_info, is_owner, nfts, MetadataValue, TransferError, TRANSFER_MAX_MEMO_LENGTH};
+
+const ICRC10: &[(&str, &str)] = &[("ICRC-10", "https://github.com/dfinity/ICRC/ICRCs/ICRC-10")];
+// Each module lists the standards it implements.
+const STANDARDS: &[&[(&str, &str)]] = &[ICRC10, nfts::STANDARDS, consent::STANDARDS];
+
+// Metadata the canister cannot derive itself, set by the owner.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct MetadataConfig {
+    // URL or data URL of the token's logo.
+    logo: Option<String>,
+    // Canister indexing this token's transactions.
+    index_canister: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+thread_local! {
+    static METADATA_CONFIG: RefCell<MetadataConfig> = const {
+        RefCell::new(MetadataConfig { logo: None, index_canister: None })
+    };
+}
+
+#[update]
+fn set_metadata_config(config: MetadataConfig) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    METADATA_CONFIG.with(|current| *current.borrow_mut() = config);
+    Ok(())
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    STANDARDS.iter()
+        .flat_map(|standards| standards.iter())
+        .map(|(name, url)| SupportedStandard { name: name.to_string(), url: url.to_string() })
+        .collect()
+}
+
+// Metadata under plain keys; `logo` and `index_canister` are left out until configured.
+pub(crate) fn metadata() -> Vec<(String, MetadataValue)> {
+    let token = get_token_info();
+    let config = METADATA_CONFIG.with(|config| config.borrow().clone());
+    let mut metadata = vec![
+        ("name".to_string(), MetadataValue::Text(token.name)),
+        ("symbol".to_string(), MetadataValue::Text(token.symbol)),
+        ("decimals".to_string(), MetadataValue::Nat(token.decimals.into())),
+        ("fee".to_string(), MetadataValue::Nat(Nat::from(token.fee))),
+        ("max_memo_length".to_string(), MetadataValue::Nat(TRANSFER_MAX_MEMO_LENGTH.into())),
+        ("max_batch_size".to_string(), MetadataValue::Nat(batch::MAX_BATCH_SIZE.into())),
+    ];
+    if let Some(logo) = config.logo {
+        metadata.push(("logo".to_string(), MetadataValue::Text(logo)));
+    }
+    if let Some(index_canister) = config.index_canister {
+        metadata.push(("index_canister".to_string(), MetadataValue::Text(index_canister.to_text())));
+    }
+    metadata
+}
+
+// The same entries under the keys the standards define; keys no standard covers get this canister's namespace.
+#[query]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    metadata()
+        .into_iter()
+        .map(|(key, value)| {
+            let key = match key.as_str() {
+                "index_canister" => "icrc106:index_principal".to_string(),
+                "max_batch_size" => "icp_token:max_batch_size".to_string(),
+                _ => format!("icrc1:{}", key),
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(METADATA_CONFIG.with(|config| config.borrow().clone()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let config = candid::decode_one(bytes)?;
+    METADATA_CONFIG.with(|state| *state.borrow_mut() = config);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    METADATA_CONFIG.with(|config| *config.borrow_mut() = MetadataConfig { logo: None, index_canister: None });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reset_state, test_utils, OWNER};
+
+    #[test]
+    fn test_metadata_and_standards() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let index = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        let names: Vec<String> = icrc10_supported_standards().into_iter().map(|standard| standard.name).collect();
+        assert_eq!(names, vec!["ICRC-10", "ICRC-7", "ICRC-37", "ICRC-21"]);
+
+        let metadata = icrc1_metadata();
+        assert!(metadata.contains(&("icrc1:symbol".to_string(), MetadataValue::Text("ICPT".to_string()))));
+        assert!(metadata.contains(&("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(0u64)))));
+        assert!(metadata.iter().all(|(key, _)| key != "icrc1:logo" && key != "icrc106:index_principal"));
+
+        let config = MetadataConfig { logo: Some("data:image/png;base64,AA==".to_string()), index_canister: Some(index) };
+        test_utils::set_caller(index);
+        assert!(matches!(set_metadata_config(config.clone()), Err(TransferError::Unauthorized)));
+        test_utils::set_caller(owner);
+        assert!(set_metadata_config(config).is_ok());
+
+        let metadata = icrc1_metadata();
+        assert!(metadata.contains(&("icrc1:logo".to_string(), MetadataValue::Text("data:image/png;base64,AA==".to_string()))));
+        assert!(metadata.contains(&("icrc106:index_principal".to_string(), MetadataValue::Text("aaaaa-aa".to_string()))));
+        assert!(metadata.contains(&("icp_token:max_batch_size".to_string(), MetadataValue::Nat(batch::MAX_BATCH_SIZE.into()))));
+
+        // The fee follows what transfers charge.
+        assert!(crate::set_transfer_fee(25).is_ok());
+        assert!(icrc1_metadata().contains(&("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(25u64)))));
+    }
+}
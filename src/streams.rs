@@ -0,0 +1,276 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{charge_fee, credit_balance, debit_balance, ensure_covers_fee, get_caller, get_time, record_event, Operation, TransferError};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum StreamStatus {
+    Active,
+    // Cancelled by the sender at `cancelled_at`; nothing streams after that.
+    Cancelled { cancelled_at: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Stream {
+    id: u64,
+    sender: Principal,
+    recipient: Principal,
+    rate_per_sec: u128,
+    deposit: u128,
+    start: u64,
+    stop: u64,
+    withdrawn: u128,
+    status: StreamStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StreamInfo {
+    stream: Stream,
+    streamed: u128,
+    // What the recipient can withdraw right now.
+    withdrawable: u128,
+    // What a cancellation right now would return to the sender.
+    refundable: u128,
+}
+
+thread_local! {
+    static STREAMS: RefCell<BTreeMap<u64, Stream>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_STREAM_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// Amount that has flowed to the recipient by `now`, in whole seconds since `start`. Nothing is stored per tick;
+// every read derives it from the clock.
+fn streamed_at(stream: &Stream, now: u64) -> u128 {
+    let end = match stream.status {
+        StreamStatus::Active => now,
+        StreamStatus::Cancelled { cancelled_at } => now.min(cancelled_at),
+    };
+    if end >= stream.stop {
+        return stream.deposit;
+    }
+    if end <= stream.start {
+        return 0;
+    }
+    let elapsed_secs = ((end - stream.start) / NANOS_PER_SEC) as u128;
+    stream.rate_per_sec.saturating_mul(elapsed_secs).min(stream.deposit)
+}
+
+fn stream_info(stream: &Stream, now: u64) -> StreamInfo {
+    let streamed = streamed_at(stream, now);
+    let refundable = match stream.status {
+        StreamStatus::Active => stream.deposit - streamed,
+        StreamStatus::Cancelled { .. } => 0,
+    };
+    StreamInfo {
+        stream: stream.clone(),
+        streamed,
+        withdrawable: streamed - stream.withdrawn,
+        refundable,
+    }
+}
+
+#[update]
+fn create_stream(recipient: Principal, rate_per_sec: u128, deposit: u128, start: u64, stop: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if start < get_time() || stop <= start {
+        return Err(TransferError::InvalidPeriod);
+    }
+    // The deposit must pay exactly the rate for the whole duration.
+    let duration_secs = ((stop - start) / NANOS_PER_SEC) as u128;
+    let expected = rate_per_sec.checked_mul(duration_secs).ok_or(TransferError::OverflowError)?;
+    if deposit == 0 || deposit != expected {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    ensure_covers_fee(&caller, deposit)?;
+    debit_balance(caller, deposit)?;
+    let id = NEXT_STREAM_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    STREAMS.with(|streams| {
+        streams.borrow_mut().insert(id, Stream {
+            id,
+            sender: caller,
+            recipient,
+            rate_per_sec,
+            deposit,
+            start,
+            stop,
+            withdrawn: 0,
+            status: StreamStatus::Active,
+        });
+    });
+
+    record_event(Operation::StreamCreate, caller, recipient, deposit);
+    charge_fee(caller)?;
+    Ok(id)
+}
+
+#[update]
+fn withdraw_from_stream(stream_id: u64) -> Result<u128, TransferError> {
+    let caller = get_caller();
+    let now = get_time();
+    let (sender, amount) = STREAMS.with(|streams| {
+        let mut streams = streams.borrow_mut();
+        let stream = streams.get_mut(&stream_id).ok_or(TransferError::StreamNotFound)?;
+        if stream.recipient != caller {
+            return Err(TransferError::Unauthorized);
+        }
+        let amount = streamed_at(stream, now) - stream.withdrawn;
+        stream.withdrawn += amount;
+        Ok((stream.sender, amount))
+    })?;
+
+    if amount > 0 {
+        credit_balance(caller, amount)?;
+        record_event(Operation::StreamWithdraw, sender, caller, amount);
+    }
+    Ok(amount)
+}
+
+// Stops the stream: the sender gets the unstreamed remainder back and the recipient keeps what has streamed so
+// far, which is paid out immediately.
+#[update]
+fn cancel_stream(stream_id: u64) -> Result<u128, TransferError> {
+    let caller = get_caller();
+    let now = get_time();
+    let (recipient, owed, refund) = STREAMS.with(|streams| {
+        let mut streams = streams.borrow_mut();
+        let stream = streams.get_mut(&stream_id).ok_or(TransferError::StreamNotFound)?;
+        if stream.sender != caller {
+            return Err(TransferError::Unauthorized);
+        }
+        if stream.status != StreamStatus::Active || now >= stream.stop {
+            return Err(TransferError::StreamNotActive);
+        }
+        let streamed = streamed_at(stream, now);
+        let owed = streamed - stream.withdrawn;
+        stream.withdrawn = streamed;
+        stream.status = StreamStatus::Cancelled { cancelled_at: now };
+        Ok((stream.recipient, owed, stream.deposit - streamed))
+    })?;
+
+    if owed > 0 {
+        credit_balance(recipient, owed)?;
+        record_event(Operation::StreamWithdraw, caller, recipient, owed);
+    }
+    if refund > 0 {
+        credit_balance(caller, refund)?;
+        record_event(Operation::StreamCancel, caller, caller, refund);
+    }
+    Ok(refund)
+}
+
+#[query]
+fn get_stream(stream_id: u64) -> Option<StreamInfo> {
+    let now = get_time();
+    STREAMS.with(|streams| streams.borrow().get(&stream_id).map(|stream| stream_info(stream, now)))
+}
+
+// Streams in which `account` is the sender or the recipient.
+#[query]
+fn get_streams(account: Principal) -> Vec<StreamInfo> {
+    let now = get_time();
+    STREAMS.with(|streams| {
+        streams.borrow()
+            .values()
+            .filter(|stream| stream.sender == account || stream.recipient == account)
+            .map(|stream| stream_info(stream, now))
+            .collect()
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        STREAMS.with(|streams| streams.take()),
+        NEXT_STREAM_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (streams, next_id) = candid::decode_one(bytes)?;
+    STREAMS.with(|state| *state.borrow_mut() = streams);
+    NEXT_STREAM_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    STREAMS.with(|streams| streams.borrow_mut().clear());
+    NEXT_STREAM_ID.with(|next_id| *next_id.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, mint, reset_state, test_utils, transfer, OWNER, TOKEN};
+
+    const SEC: u64 = NANOS_PER_SEC;
+
+    fn setup() -> (Principal, Principal) {
+        reset_state();
+        let sender = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let recipient = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = sender);
+        test_utils::set_caller(sender);
+        assert!(mint(sender, 1000).is_ok());
+        (sender, recipient)
+    }
+
+    #[test]
+    fn test_withdraw_accrued() {
+        let (sender, recipient) = setup();
+
+        assert!(matches!(create_stream(recipient, 10, 999, 0, 100 * SEC), Err(TransferError::InvalidAmount)));
+        assert!(matches!(create_stream(recipient, 10, 1000, 100 * SEC, 0), Err(TransferError::InvalidPeriod)));
+        let id = create_stream(recipient, 10, 1000, 0, 100 * SEC).unwrap();
+        // The deposit is held by the stream, so it cannot be spent twice.
+        assert!(matches!(transfer(recipient, 1), Err(TransferError::InsufficientBalance)));
+
+        test_utils::set_caller(recipient);
+        test_utils::set_time(25 * SEC + SEC / 2);
+        assert_eq!(get_stream(id).unwrap().withdrawable, 250);
+        assert_eq!(withdraw_from_stream(id).unwrap(), 250);
+        assert_eq!(withdraw_from_stream(id).unwrap(), 0);
+
+        test_utils::set_time(500 * SEC);
+        assert_eq!(withdraw_from_stream(id).unwrap(), 750);
+        assert_eq!(balance_of(&recipient), 1000);
+        assert_eq!(balance_of(&sender), 0);
+
+        test_utils::set_caller(sender);
+        assert!(matches!(withdraw_from_stream(id), Err(TransferError::Unauthorized)));
+        assert!(matches!(cancel_stream(id), Err(TransferError::StreamNotActive)));
+    }
+
+    #[test]
+    fn test_cancel_refunds_remainder() {
+        let (sender, recipient) = setup();
+        let id = create_stream(recipient, 10, 1000, 10 * SEC, 110 * SEC).unwrap();
+
+        test_utils::set_time(40 * SEC);
+        test_utils::set_caller(recipient);
+        assert!(matches!(cancel_stream(id), Err(TransferError::Unauthorized)));
+        assert_eq!(withdraw_from_stream(id).unwrap(), 300);
+
+        test_utils::set_time(70 * SEC);
+        test_utils::set_caller(sender);
+        assert_eq!(get_stream(id).unwrap().refundable, 400);
+        assert_eq!(cancel_stream(id).unwrap(), 400);
+        assert!(matches!(cancel_stream(id), Err(TransferError::StreamNotActive)));
+
+        test_utils::set_time(200 * SEC);
+        let info = get_stream(id).unwrap();
+        assert_eq!((info.streamed, info.withdrawable, info.refundable), (600, 0, 0));
+        assert_eq!(balance_of(&recipient), 600);
+        assert_eq!(balance_of(&sender), 400);
+        TOKEN.with(|token| assert_eq!(token.borrow().total_supply, 1_000_000_000_000_001_000));
+    }
+}
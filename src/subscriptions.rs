@@ -0,0 +1,350 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{get_caller, get_time, schedule_at, transfer_from, TransferError};
+
+// A failed payment is retried this often, up to `MAX_RETRIES` times before the subscription lapses.
+const RETRY_INTERVAL: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_RETRIES: u32 = 3;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum SubscriptionStatus {
+    Active,
+    // The last payment failed and is being retried.
+    PastDue { failed_attempts: u32 },
+    // Retries ran out; no further payments are attempted.
+    Lapsed,
+    Cancelled { by: Principal },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Subscription {
+    id: u64,
+    payer: Principal,
+    merchant: Principal,
+    amount: u128,
+    period: u64,
+    // Start of the next billing period to be paid.
+    next_payment_at: u64,
+    // When the next payment (or retry) is attempted.
+    next_attempt_at: u64,
+    payments: u64,
+    last_block_index: Option<u64>,
+    last_error: Option<TransferError>,
+    status: SubscriptionStatus,
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<BTreeMap<u64, Subscription>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_SUBSCRIPTION_ID: RefCell<u64> = const { RefCell::new(0) };
+    // The pending collection timer of each billable subscription.
+    static SUBSCRIPTION_TIMERS: RefCell<BTreeMap<u64, TimerId>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Replaces the pending collection timer of a subscription, so an early `collect` does not leave the old one behind.
+fn schedule_collection(subscription_id: u64, timestamp: u64) {
+    stop_collection(subscription_id);
+    let timer_id = schedule_at(timestamp, move || collect_due(subscription_id));
+    SUBSCRIPTION_TIMERS.with(|timers| timers.borrow_mut().insert(subscription_id, timer_id));
+}
+
+fn stop_collection(subscription_id: u64) {
+    if let Some(timer_id) = SUBSCRIPTION_TIMERS.with(|timers| timers.borrow_mut().remove(&subscription_id)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
+
+fn is_billable(status: &SubscriptionStatus) -> bool {
+    matches!(status, SubscriptionStatus::Active | SubscriptionStatus::PastDue { .. })
+}
+
+// Attempts the due payment of a subscription and schedules the next attempt. A failed payment is recorded on the
+// subscription for retry and also returned.
+fn charge(subscription_id: u64) -> Result<u64, TransferError> {
+    let now = get_time();
+    let (payer, merchant, amount, next_payment_at) = SUBSCRIPTIONS.with(|subscriptions| {
+        let subscriptions = subscriptions.borrow();
+        let subscription = subscriptions.get(&subscription_id).ok_or(TransferError::SubscriptionNotFound)?;
+        if !is_billable(&subscription.status) {
+            return Err(TransferError::SubscriptionNotActive);
+        }
+        if now < subscription.next_attempt_at {
+            return Err(TransferError::PaymentNotDue);
+        }
+        let next_payment_at = subscription.next_payment_at
+            .checked_add(subscription.period)
+            .ok_or(TransferError::OverflowError)?;
+        Ok((subscription.payer, subscription.merchant, subscription.amount, next_payment_at))
+    })?;
+
+    let result = transfer_from(payer, merchant, amount);
+    let next_attempt_at = SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        let subscription = subscriptions.get_mut(&subscription_id).ok_or(TransferError::SubscriptionNotFound)?;
+        match &result {
+            Ok(block_index) => {
+                subscription.payments += 1;
+                subscription.last_block_index = Some(*block_index);
+                subscription.last_error = None;
+                subscription.status = SubscriptionStatus::Active;
+                subscription.next_payment_at = next_payment_at;
+                subscription.next_attempt_at = subscription.next_payment_at;
+            }
+            Err(err) => {
+                let failed_attempts = match subscription.status {
+                    SubscriptionStatus::PastDue { failed_attempts } => failed_attempts + 1,
+                    _ => 1,
+                };
+                subscription.last_error = Some(err.clone());
+                subscription.status = if failed_attempts > MAX_RETRIES {
+                    SubscriptionStatus::Lapsed
+                } else {
+                    SubscriptionStatus::PastDue { failed_attempts }
+                };
+                subscription.next_attempt_at = now.saturating_add(RETRY_INTERVAL);
+            }
+        }
+        Ok(is_billable(&subscription.status).then_some(subscription.next_attempt_at))
+    })?;
+    match next_attempt_at {
+        Some(next_attempt_at) => schedule_collection(subscription_id, next_attempt_at),
+        None => stop_collection(subscription_id),
+    }
+    result
+}
+
+fn collect_due(subscription_id: u64) {
+    // Timers left over from a payment the merchant already collected find nothing due.
+    if let Err(err) = charge(subscription_id) {
+        ic_cdk::println!("Subscription {} not collected: {:?}", subscription_id, err);
+    }
+}
+
+// Authorizes `merchant` to collect `amount` every `period` nanoseconds from the caller. The first period is paid
+// immediately, and nothing is created if that payment fails.
+#[update]
+fn subscribe(merchant: Principal, amount: u128, period: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    if period == 0 {
+        return Err(TransferError::InvalidPeriod);
+    }
+    let next_payment_at = get_time().checked_add(period).ok_or(TransferError::OverflowError)?;
+
+    let block_index = transfer_from(caller, merchant, amount)?;
+    let id = NEXT_SUBSCRIPTION_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow_mut().insert(id, Subscription {
+            id,
+            payer: caller,
+            merchant,
+            amount,
+            period,
+            next_payment_at,
+            next_attempt_at: next_payment_at,
+            payments: 1,
+            last_block_index: Some(block_index),
+            last_error: None,
+            status: SubscriptionStatus::Active,
+        });
+    });
+    schedule_collection(id, next_payment_at);
+    Ok(id)
+}
+
+// Lets the merchant collect a due payment without waiting for the timer. Returns the block index of the payment.
+#[update]
+fn collect(subscription_id: u64) -> Result<u64, TransferError> {
+    let caller = get_caller();
+    let merchant = get_subscription(subscription_id).ok_or(TransferError::SubscriptionNotFound)?.merchant;
+    if caller != merchant {
+        return Err(TransferError::Unauthorized);
+    }
+    charge(subscription_id)
+}
+
+// Either the payer or the merchant can cancel; no further payments are taken.
+#[update]
+fn cancel_subscription(subscription_id: u64) -> Result<(), TransferError> {
+    let caller = get_caller();
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut subscriptions = subscriptions.borrow_mut();
+        let subscription = subscriptions.get_mut(&subscription_id).ok_or(TransferError::SubscriptionNotFound)?;
+        if caller != subscription.payer && caller != subscription.merchant {
+            return Err(TransferError::Unauthorized);
+        }
+        if !is_billable(&subscription.status) {
+            return Err(TransferError::SubscriptionNotActive);
+        }
+        subscription.status = SubscriptionStatus::Cancelled { by: caller };
+        Ok(())
+    })?;
+    stop_collection(subscription_id);
+    Ok(())
+}
+
+#[query]
+fn get_subscription(subscription_id: u64) -> Option<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&subscription_id).cloned())
+}
+
+// Subscriptions in which `account` is the payer or the merchant.
+#[query]
+fn get_subscriptions(account: Principal) -> Vec<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow()
+            .values()
+            .filter(|subscription| subscription.payer == account || subscription.merchant == account)
+            .cloned()
+            .collect()
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        SUBSCRIPTIONS.with(|subscriptions| subscriptions.take()),
+        NEXT_SUBSCRIPTION_ID.with(|next_id| next_id.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (subscriptions, next_id) = candid::decode_one(bytes)?;
+    SUBSCRIPTIONS.with(|state| *state.borrow_mut() = subscriptions);
+    NEXT_SUBSCRIPTION_ID.with(|state| *state.borrow_mut() = next_id);
+    Ok(())
+}
+
+// Re-arms the collection timer of every billable subscription after an upgrade.
+pub(crate) fn resume_timers() {
+    let due: Vec<(u64, u64)> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .filter(|(_, subscription)| is_billable(&subscription.status))
+            .map(|(id, subscription)| (*id, subscription.next_attempt_at))
+            .collect()
+    });
+    for (id, next_attempt_at) in due {
+        schedule_collection(id, next_attempt_at);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().clear());
+    NEXT_SUBSCRIPTION_ID.with(|next_id| *next_id.borrow_mut() = 0);
+    SUBSCRIPTION_TIMERS.with(|timers| timers.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, mint, reset_state, test_utils, OWNER};
+
+    fn has_timer(subscription_id: u64) -> bool {
+        SUBSCRIPTION_TIMERS.with(|timers| timers.borrow().contains_key(&subscription_id))
+    }
+
+    const PERIOD: u64 = 30 * RETRY_INTERVAL;
+
+    fn setup(balance: u128) -> (Principal, Principal) {
+        reset_state();
+        let payer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let merchant = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = payer);
+        test_utils::set_caller(payer);
+        assert!(mint(payer, balance).is_ok());
+        (payer, merchant)
+    }
+
+    #[test]
+    fn test_collect_and_cancel() {
+        let (payer, merchant) = setup(1000);
+
+        assert!(matches!(subscribe(merchant, 100, 0), Err(TransferError::InvalidPeriod)));
+        assert!(matches!(subscribe(merchant, 2000, PERIOD), Err(TransferError::InsufficientBalance)));
+        let id = subscribe(merchant, 100, PERIOD).unwrap();
+        assert_eq!(balance_of(&merchant), 100);
+        assert!(has_timer(id));
+
+        test_utils::set_caller(merchant);
+        assert!(matches!(collect(id), Err(TransferError::PaymentNotDue)));
+        test_utils::set_time(PERIOD);
+        let block_index = collect(id).unwrap();
+        assert!(matches!(collect(id), Err(TransferError::PaymentNotDue)));
+
+        // The timer collects the next period on its own.
+        test_utils::set_time(2 * PERIOD);
+        collect_due(id);
+        let subscription = get_subscription(id).unwrap();
+        assert_eq!((subscription.payments, subscription.next_payment_at), (3, 3 * PERIOD));
+        assert!(subscription.last_block_index > Some(block_index));
+
+        test_utils::set_caller(payer);
+        assert!(matches!(collect(id), Err(TransferError::Unauthorized)));
+        assert!(cancel_subscription(id).is_ok());
+        assert!(!has_timer(id));
+        test_utils::set_time(3 * PERIOD);
+        collect_due(id);
+        assert_eq!(get_subscription(id).unwrap().status, SubscriptionStatus::Cancelled { by: payer });
+        assert_eq!(balance_of(&merchant), 300);
+    }
+
+    #[test]
+    fn test_retries_until_lapsed() {
+        let (payer, merchant) = setup(150);
+        let id = subscribe(merchant, 100, PERIOD).unwrap();
+
+        test_utils::set_time(PERIOD);
+        collect_due(id);
+        assert_eq!(get_subscription(id).unwrap().status, SubscriptionStatus::PastDue { failed_attempts: 1 });
+        test_utils::set_time(PERIOD + RETRY_INTERVAL / 2);
+        collect_due(id);
+        assert_eq!(get_subscription(id).unwrap().status, SubscriptionStatus::PastDue { failed_attempts: 1 });
+
+        // A retry after a top-up catches up and keeps the original billing dates.
+        assert!(mint(payer, 50).is_ok());
+        test_utils::set_time(PERIOD + RETRY_INTERVAL);
+        collect_due(id);
+        let subscription = get_subscription(id).unwrap();
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+        assert_eq!(subscription.next_attempt_at, 2 * PERIOD);
+
+        assert_eq!(balance_of(&payer), 0);
+        test_utils::set_time(2 * PERIOD);
+        for retry in 0..=MAX_RETRIES {
+            collect_due(id);
+            test_utils::set_time(2 * PERIOD + (retry as u64 + 1) * RETRY_INTERVAL);
+        }
+        let subscription = get_subscription(id).unwrap();
+        assert_eq!(subscription.status, SubscriptionStatus::Lapsed);
+        assert!(!has_timer(id));
+        assert!(matches!(subscription.last_error, Some(TransferError::InsufficientBalance)));
+        assert!(matches!(cancel_subscription(id), Err(TransferError::SubscriptionNotActive)));
+        assert_eq!(balance_of(&merchant), 200);
+    }
+
+    #[test]
+    fn test_period_overflow() {
+        let (_, merchant) = setup(1000);
+
+        test_utils::set_time(1);
+        assert!(matches!(subscribe(merchant, 100, u64::MAX), Err(TransferError::OverflowError)));
+        assert_eq!(balance_of(&merchant), 0);
+
+        // A period that fits once but not twice stops before the second payment is taken.
+        let id = subscribe(merchant, 100, u64::MAX - 1).unwrap();
+        assert_eq!(get_subscription(id).unwrap().next_payment_at, u64::MAX);
+        test_utils::set_time(u64::MAX);
+        test_utils::set_caller(merchant);
+        assert!(matches!(collect(id), Err(TransferError::OverflowError)));
+        assert_eq!(balance_of(&merchant), 100);
+    }
+}
@@ -0,0 +1,320 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{
+    burn, credit_wallet, debit_wallet, get_balance, get_caller, get_token_info, is_owner, mint,
+    record_token_event, token_balance_of, transfer, Balance, Operation, Token, TransferError, TransferEvent, DEFAULT_TOKEN,
+    TRANSFER_EVENTS, WALLETS,
+};
+use crate::wrap::{self, WRAPPED_TOKEN};
+
+#[derive(CandidType, Deserialize, Clone)]
+struct RegisterTokenArgs {
+    name: String,
+    symbol: String,
+    decimals: u8,
+    initial_supply: u128,
+    minter: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct RegisteredToken {
+    token: Token,
+    minter: Principal,
+}
+
+thread_local! {
+    // Keyed by symbol, which is also the key of the token in `Wallet.balances`.
+    static TOKEN_REGISTRY: RefCell<HashMap<String, RegisteredToken>> = RefCell::new(HashMap::new());
+}
+
+fn registered_token(token_id: &str) -> Result<RegisteredToken, TransferError> {
+    TOKEN_REGISTRY.with(|registry| registry.borrow().get(token_id).cloned().ok_or(TransferError::TokenNotFound))
+}
+
+fn update_supply(token_id: &str, f: impl FnOnce(u128) -> Option<u128>) -> Result<(), TransferError> {
+    TOKEN_REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let entry = registry.get_mut(token_id).ok_or(TransferError::TokenNotFound)?;
+        entry.token.total_supply = f(entry.token.total_supply).ok_or(TransferError::OverflowError)?;
+        Ok(())
+    })
+}
+
+#[update]
+fn register_token(args: RegisterTokenArgs) -> Result<String, TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    if args.symbol.is_empty() || args.decimals > 38 {
+        return Err(TransferError::InvalidTokenMetadata);
+    }
+    let exists = args.symbol == DEFAULT_TOKEN
+        || args.symbol == WRAPPED_TOKEN
+        || TOKEN_REGISTRY.with(|registry| registry.borrow().contains_key(&args.symbol));
+    if exists {
+        return Err(TransferError::TokenAlreadyExists);
+    }
+
+    let token_id = args.symbol.clone();
+    credit_wallet(args.minter, &token_id, args.initial_supply)?;
+    TOKEN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(token_id.clone(), RegisteredToken {
+            token: Token {
+                name: args.name,
+                symbol: args.symbol,
+                decimals: args.decimals,
+                total_supply: args.initial_supply,
+                fee: 0,
+            },
+            minter: args.minter,
+        });
+    });
+    if args.initial_supply > 0 {
+        record_token_event(&token_id, Operation::Mint, get_caller(), args.minter, args.initial_supply);
+    }
+
+    ic_cdk::println!("Registered token {} with minter {:?}", token_id, args.minter);
+    Ok(token_id)
+}
+
+#[query]
+fn get_token_metadata(token_id: String) -> Option<Token> {
+    if token_id == DEFAULT_TOKEN {
+        return Some(get_token_info());
+    }
+    if token_id == WRAPPED_TOKEN {
+        return Some(wrap::wrapped_token());
+    }
+    registered_token(&token_id).ok().map(|entry| entry.token)
+}
+
+#[query]
+fn list_tokens() -> Vec<Token> {
+    let mut tokens: Vec<Token> = TOKEN_REGISTRY.with(|registry| {
+        registry.borrow().values().map(|entry| entry.token.clone()).collect()
+    });
+    tokens.push(wrap::wrapped_token());
+    tokens.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    tokens.insert(0, get_token_info());
+    tokens
+}
+
+#[update]
+fn transfer_token(token_id: String, to: Principal, amount: u128) -> Result<bool, TransferError> {
+    if token_id == DEFAULT_TOKEN {
+        return transfer(to, amount);
+    }
+    if token_id == WRAPPED_TOKEN {
+        return wrap::transfer_wrapped(get_caller(), to, amount).map(|_| true);
+    }
+    registered_token(&token_id)?;
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    debit_wallet(caller, &token_id, amount, 0)?;
+    if let Err(err) = credit_wallet(to, &token_id, amount) {
+        credit_wallet(caller, &token_id, amount)?;
+        return Err(err);
+    }
+
+    record_token_event(&token_id, Operation::Transfer, caller, to, amount);
+    Ok(true)
+}
+
+#[update]
+fn mint_token(token_id: String, to: Principal, amount: u128) -> Result<bool, TransferError> {
+    if token_id == DEFAULT_TOKEN {
+        return mint(to, amount);
+    }
+    // WICP is only minted by wrapping ICP.
+    if token_id == WRAPPED_TOKEN {
+        return Err(TransferError::Unauthorized);
+    }
+    let caller = get_caller();
+    if registered_token(&token_id)?.minter != caller {
+        return Err(TransferError::Unauthorized);
+    }
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    credit_wallet(to, &token_id, amount)?;
+    if let Err(err) = update_supply(&token_id, |supply| supply.checked_add(amount)) {
+        debit_wallet(to, &token_id, amount, 0)?;
+        return Err(err);
+    }
+
+    record_token_event(&token_id, Operation::Mint, caller, to, amount);
+    Ok(true)
+}
+
+#[update]
+fn burn_token(token_id: String, amount: u128) -> Result<bool, TransferError> {
+    if token_id == DEFAULT_TOKEN {
+        return burn(amount);
+    }
+    // Burning WICP outside `unwrap` would strand its ICP in the reserves.
+    if token_id == WRAPPED_TOKEN {
+        return Err(TransferError::Unauthorized);
+    }
+    registered_token(&token_id)?;
+    let caller = get_caller();
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+
+    debit_wallet(caller, &token_id, amount, 0)?;
+    update_supply(&token_id, |supply| supply.checked_sub(amount))?;
+
+    record_token_event(&token_id, Operation::Burn, caller, caller, amount);
+    Ok(true)
+}
+
+#[query]
+fn get_token_balance(token_id: String, owner: Principal) -> Result<Balance, TransferError> {
+    if token_id == DEFAULT_TOKEN {
+        return Ok(get_balance(owner));
+    }
+    if token_id != WRAPPED_TOKEN {
+        registered_token(&token_id)?;
+    }
+    let total = token_balance_of(&owner, &token_id);
+    Ok(Balance {
+        total,
+        locked: 0,
+        available: total,
+        escrowed: 0,
+    })
+}
+
+// Every token the wallet holds a non-zero balance of, ordered by token id.
+#[query]
+pub(crate) fn get_balances(owner: Principal) -> Vec<(String, u128)> {
+    let mut balances: Vec<(String, u128)> = WALLETS.with(|wallets| {
+        wallets.borrow()
+            .get(&owner)
+            .map(|wallet| {
+                wallet.balances.iter()
+                    .filter(|(_, balance)| **balance > 0)
+                    .map(|(token_id, balance)| (token_id.clone(), *balance))
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    balances.sort();
+    balances
+}
+
+#[query]
+fn get_token_history(token_id: String) -> Vec<TransferEvent> {
+    TRANSFER_EVENTS.with(|events| {
+        events.borrow()
+            .iter()
+            .filter(|event| event.token == token_id)
+            .cloned()
+            .collect()
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one(TOKEN_REGISTRY.with(|registry| registry.take()))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let registry = candid::decode_one(bytes)?;
+    TOKEN_REGISTRY.with(|state| *state.borrow_mut() = registry);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    TOKEN_REGISTRY.with(|registry| registry.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reset_state, test_utils, OWNER};
+
+    fn register_gold(minter: Principal) -> String {
+        register_token(RegisterTokenArgs {
+            name: "Gold".to_string(),
+            symbol: "GLD".to_string(),
+            decimals: 2,
+            initial_supply: 500,
+            minter,
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_register_and_use_token() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let minter = Principal::from_text("aaaaa-aa").unwrap();
+        let user = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(minter);
+        assert!(matches!(
+            register_token(RegisterTokenArgs {
+                name: "Gold".to_string(),
+                symbol: "GLD".to_string(),
+                decimals: 2,
+                initial_supply: 0,
+                minter,
+            }),
+            Err(TransferError::Unauthorized)
+        ));
+
+        test_utils::set_caller(owner);
+        let gold = register_gold(minter);
+        assert!(matches!(
+            register_token(RegisterTokenArgs {
+                name: "Copy".to_string(),
+                symbol: DEFAULT_TOKEN.to_string(),
+                decimals: 8,
+                initial_supply: 0,
+                minter,
+            }),
+            Err(TransferError::TokenAlreadyExists)
+        ));
+        assert!(matches!(
+            register_token(RegisterTokenArgs {
+                name: "Nameless".to_string(),
+                symbol: String::new(),
+                decimals: 8,
+                initial_supply: 0,
+                minter,
+            }),
+            Err(TransferError::InvalidTokenMetadata)
+        ));
+        assert!(matches!(mint_token(gold.clone(), user, 10), Err(TransferError::Unauthorized)));
+        assert!(mint_token(DEFAULT_TOKEN.to_string(), minter, 70).is_ok());
+
+        test_utils::set_caller(minter);
+        assert!(mint_token(gold.clone(), minter, 100).is_ok());
+        assert!(transfer_token(gold.clone(), user, 150).is_ok());
+        assert!(matches!(transfer_token(gold.clone(), user, 1000), Err(TransferError::InsufficientBalance)));
+        assert!(matches!(transfer_token("NOPE".to_string(), user, 1), Err(TransferError::TokenNotFound)));
+
+        test_utils::set_caller(user);
+        assert!(matches!(burn_token(gold.clone(), 0), Err(TransferError::InvalidAmount)));
+        assert!(burn_token(gold.clone(), 50).is_ok());
+
+        assert_eq!(get_token_balance(gold.clone(), minter).unwrap().total, 450);
+        assert_eq!(get_token_balance(gold.clone(), user).unwrap().total, 100);
+        assert_eq!(get_token_metadata(gold.clone()).unwrap().total_supply, 550);
+        assert_eq!(get_balances(minter), vec![("GLD".to_string(), 450), (DEFAULT_TOKEN.to_string(), 70)]);
+
+        let operations: Vec<Operation> = get_token_history(gold).into_iter().map(|event| event.operation).collect();
+        assert_eq!(operations, vec![Operation::Mint, Operation::Mint, Operation::Transfer, Operation::Burn]);
+        assert_eq!(get_token_history(DEFAULT_TOKEN.to_string()).len(), 1);
+        let symbols: Vec<String> = list_tokens().into_iter().map(|token| token.symbol).collect();
+        assert_eq!(symbols, vec!["ICPT", "GLD", "WICP"]);
+    }
+}
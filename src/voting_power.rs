@@ -0,0 +1,194 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{balance_of, get_caller, get_time, snapshots, TransferError, WALLETS};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct VotingCheckpoint {
+    timestamp: u64,
+    // Number of balance snapshots taken when the checkpoint was written, so governance can read votes as of a
+    // snapshot even within one timestamp.
+    snapshot_id: u64,
+    votes: u128,
+}
+
+thread_local! {
+    // Accounts without an entry delegate to themselves.
+    static DELEGATES: RefCell<HashMap<Principal, Principal>> = RefCell::new(HashMap::new());
+    static VOTING_CHECKPOINTS: RefCell<HashMap<Principal, Vec<VotingCheckpoint>>> = RefCell::new(HashMap::new());
+}
+
+fn delegate_of(owner: Principal) -> Principal {
+    DELEGATES.with(|delegates| delegates.borrow().get(&owner).cloned().unwrap_or(owner))
+}
+
+pub(crate) fn current_votes(delegatee: &Principal) -> u128 {
+    VOTING_CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow()
+            .get(delegatee)
+            .and_then(|entries| entries.last())
+            .map(|checkpoint| checkpoint.votes)
+            .unwrap_or(0)
+    })
+}
+
+fn write_checkpoint(delegatee: Principal, votes: u128) {
+    let timestamp = get_time();
+    let snapshot_id = snapshots::current_snapshot_id();
+    VOTING_CHECKPOINTS.with(|checkpoints| {
+        let mut checkpoints = checkpoints.borrow_mut();
+        let entries = checkpoints.entry(delegatee).or_default();
+        match entries.last_mut() {
+            Some(last) if last.timestamp == timestamp && last.snapshot_id == snapshot_id => last.votes = votes,
+            _ => entries.push(VotingCheckpoint { timestamp, snapshot_id, votes }),
+        }
+    });
+}
+
+fn move_votes(from_delegate: Option<Principal>, to_delegate: Option<Principal>, amount: u128) {
+    if amount == 0 || from_delegate == to_delegate {
+        return;
+    }
+    if let Some(delegatee) = from_delegate {
+        write_checkpoint(delegatee, current_votes(&delegatee).saturating_sub(amount));
+    }
+    if let Some(delegatee) = to_delegate {
+        write_checkpoint(delegatee, current_votes(&delegatee).saturating_add(amount));
+    }
+}
+
+// Delegated voting power when snapshot `snapshot_id` was taken.
+pub(crate) fn votes_at_snapshot(delegatee: &Principal, snapshot_id: u64) -> u128 {
+    VOTING_CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow()
+            .get(delegatee)
+            .and_then(|entries| {
+                let index = entries.partition_point(|checkpoint| checkpoint.snapshot_id < snapshot_id);
+                index.checked_sub(1).map(|index| entries[index].votes)
+            })
+            .unwrap_or(0)
+    })
+}
+
+// Called after every balance change: `None` stands for tokens entering or leaving circulation.
+pub(crate) fn move_delegated_votes(from_owner: Option<Principal>, to_owner: Option<Principal>, amount: u128) {
+    move_votes(from_owner.map(delegate_of), to_owner.map(delegate_of), amount);
+}
+
+#[update]
+pub(crate) fn delegate(to: Principal) -> Result<(), TransferError> {
+    let caller = get_caller();
+    if !WALLETS.with(|wallets| wallets.borrow().contains_key(&caller)) {
+        return Err(TransferError::SenderWalletNotFound);
+    }
+    let previous = delegate_of(caller);
+    if previous == to {
+        return Ok(());
+    }
+
+    DELEGATES.with(|delegates| {
+        let mut delegates = delegates.borrow_mut();
+        if to == caller {
+            delegates.remove(&caller);
+        } else {
+            delegates.insert(caller, to);
+        }
+    });
+    move_votes(Some(previous), Some(to), balance_of(&caller));
+
+    ic_cdk::println!("{:?} delegated votes from {:?} to {:?}", caller, previous, to);
+    Ok(())
+}
+
+#[query]
+fn get_delegate(owner: Principal) -> Principal {
+    delegate_of(owner)
+}
+
+#[query]
+fn get_voting_power(owner: Principal) -> u128 {
+    current_votes(&owner)
+}
+
+// Voting power at the end of `timestamp`.
+#[query]
+fn get_past_voting_power(owner: Principal, timestamp: u64) -> u128 {
+    VOTING_CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow()
+            .get(&owner)
+            .and_then(|entries| {
+                let index = entries.partition_point(|checkpoint| checkpoint.timestamp <= timestamp);
+                index.checked_sub(1).map(|index| entries[index].votes)
+            })
+            .unwrap_or(0)
+    })
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        DELEGATES.with(|delegates| delegates.take()),
+        VOTING_CHECKPOINTS.with(|checkpoints| checkpoints.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (delegates, checkpoints) = candid::decode_one(bytes)?;
+    DELEGATES.with(|state| *state.borrow_mut() = delegates);
+    VOTING_CHECKPOINTS.with(|state| *state.borrow_mut() = checkpoints);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    DELEGATES.with(|delegates| delegates.borrow_mut().clear());
+    VOTING_CHECKPOINTS.with(|checkpoints| checkpoints.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{burn, mint, reset_state, test_utils, transfer, OWNER};
+
+    #[test]
+    fn test_voting_power_follows_balances_and_delegation() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let alice = Principal::from_text("aaaaa-aa").unwrap();
+        let bob = Principal::anonymous();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+
+        test_utils::set_caller(owner);
+        test_utils::set_time(10);
+        assert!(mint(alice, 1000).is_ok());
+        assert_eq!(get_voting_power(alice), 1000);
+
+        test_utils::set_caller(bob);
+        assert!(matches!(delegate(alice), Err(TransferError::SenderWalletNotFound)));
+
+        test_utils::set_caller(alice);
+        test_utils::set_time(20);
+        assert!(delegate(bob).is_ok());
+        assert_eq!(get_delegate(alice), bob);
+        assert_eq!(get_voting_power(alice), 0);
+        assert_eq!(get_voting_power(bob), 1000);
+
+        test_utils::set_time(30);
+        assert!(transfer(owner, 300).is_ok());
+        assert!(burn(200).is_ok());
+        assert_eq!(get_voting_power(bob), 500);
+        assert_eq!(get_voting_power(owner), 300);
+
+        test_utils::set_time(40);
+        assert!(delegate(alice).is_ok());
+        assert_eq!(get_voting_power(alice), 500);
+        assert_eq!(get_voting_power(bob), 0);
+
+        assert_eq!(get_past_voting_power(alice, 5), 0);
+        assert_eq!(get_past_voting_power(alice, 15), 1000);
+        assert_eq!(get_past_voting_power(bob, 25), 1000);
+        assert_eq!(get_past_voting_power(bob, 30), 500);
+        assert_eq!(get_past_voting_power(bob, 40), 0);
+    }
+}
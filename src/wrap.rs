@@ -0,0 +1,384 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use icp_token_wallet::icp_ledger::{self, Account, TransferArg, TransferFromArgs};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::factory::call_failed;
+use crate::{
+    credit_wallet, debit_wallet, deposits, get_caller, is_owner, record_token_event, token_balance_of, Operation, Token,
+    TransferError,
+};
+
+// Key of wrapped ICP in `Wallet.balances`. It is a token of its own, so every unit is backed by ICP in the
+// reserves; ICPT can also be minted by the owner, by governance and as staking rewards.
+pub(crate) const WRAPPED_TOKEN: &str = "WICP";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct WrapConfig {
+    // ICP ledger holding the reserves.
+    pub(crate) ledger: Principal,
+    // ICP transfer fee, in e8s.
+    pub(crate) fee: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct WrapInfo {
+    config: Option<WrapConfig>,
+    wrapped_supply: u128,
+    pending_unwraps: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct ReserveReport {
+    reserves: u128,
+    wrapped_supply: u128,
+    pending_unwraps: u128,
+    // Credited deposits still waiting in deposit subaccounts.
+    pending_sweeps: u128,
+    // Whether the reserves and pending sweeps cover exactly the wrapped supply plus the unwraps still being
+    // paid out.
+    balanced: bool,
+}
+
+thread_local! {
+    static WRAP_CONFIG: RefCell<Option<WrapConfig>> = const { RefCell::new(None) };
+    // WICP minted against ICP held in the canister's ledger account.
+    static WRAPPED_SUPPLY: RefCell<u128> = const { RefCell::new(0) };
+    // Burned by `unwrap` but not yet paid out by the ICP ledger.
+    static PENDING_UNWRAPS: RefCell<u128> = const { RefCell::new(0) };
+}
+
+// The ICP ledger calls wrapping needs, behind a trait so the tests can run against an in-memory ledger.
+trait IcpLedger {
+    fn fee(&self) -> u128;
+    // Pulls `amount` from `from` into the canister's account under an ICRC-2 approval; `from` pays the fee.
+    async fn transfer_from(&self, from: Principal, amount: u128) -> Result<u64, TransferError>;
+    // Pays `amount` out of the canister's account; `to` receives it minus the fee.
+    async fn transfer(&self, to: Principal, amount: u128) -> Result<u64, TransferError>;
+    async fn reserves(&self) -> Result<u128, TransferError>;
+}
+
+pub(crate) struct LedgerCanister(pub(crate) WrapConfig);
+
+pub(crate) fn block_index(index: Nat) -> u64 {
+    index.0.try_into().unwrap_or(u64::MAX)
+}
+
+impl IcpLedger for LedgerCanister {
+    fn fee(&self) -> u128 {
+        self.0.fee
+    }
+
+    async fn transfer_from(&self, from: Principal, amount: u128) -> Result<u64, TransferError> {
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: Account::new(from),
+            to: Account::new(ic_cdk::id()),
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(self.0.fee)),
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, icp_ledger::TransferFromError>,) =
+            ic_cdk::call(self.0.ledger, "icrc2_transfer_from", (args,)).await.map_err(call_failed)?;
+        result.map(block_index).map_err(|err| TransferError::IcpTransferFailed(format!("{:?}", err)))
+    }
+
+    async fn transfer(&self, to: Principal, amount: u128) -> Result<u64, TransferError> {
+        let args = TransferArg {
+            from_subaccount: None,
+            to: Account::new(to),
+            amount: Nat::from(amount - self.0.fee),
+            fee: Some(Nat::from(self.0.fee)),
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, icp_ledger::TransferError>,) =
+            ic_cdk::call(self.0.ledger, "icrc1_transfer", (args,)).await.map_err(call_failed)?;
+        result.map(block_index).map_err(|err| TransferError::IcpTransferFailed(format!("{:?}", err)))
+    }
+
+    async fn reserves(&self) -> Result<u128, TransferError> {
+        let (balance,): (Nat,) = ic_cdk::call(self.0.ledger, "icrc1_balance_of", (Account::new(ic_cdk::id()),))
+            .await
+            .map_err(call_failed)?;
+        Ok(balance.0.try_into().unwrap_or(u128::MAX))
+    }
+}
+
+pub(crate) fn ledger() -> Result<LedgerCanister, TransferError> {
+    WRAP_CONFIG.with(|config| config.borrow().clone()).map(LedgerCanister).ok_or(TransferError::IcpLedgerNotSet)
+}
+
+fn adjust(counter: &'static LocalKey<RefCell<u128>>, f: impl FnOnce(u128) -> Option<u128>) -> Result<(), TransferError> {
+    counter.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter = f(*counter).ok_or(TransferError::OverflowError)?;
+        Ok(())
+    })
+}
+
+async fn wrap_with(ledger: &impl IcpLedger, caller: Principal, amount: u128) -> Result<u64, TransferError> {
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    // Fail before taking the ICP rather than after.
+    WRAPPED_SUPPLY.with(|wrapped| wrapped.borrow().checked_add(amount)).ok_or(TransferError::OverflowError)?;
+
+    ledger.transfer_from(caller, amount).await?;
+    mint_wrapped(caller, amount)
+}
+
+// Mints WICP against ICP the canister has received.
+pub(crate) fn mint_wrapped(to: Principal, amount: u128) -> Result<u64, TransferError> {
+    adjust(&WRAPPED_SUPPLY, |supply| supply.checked_add(amount))?;
+    if let Err(err) = credit_wallet(to, WRAPPED_TOKEN, amount) {
+        adjust(&WRAPPED_SUPPLY, |supply| supply.checked_sub(amount))?;
+        return Err(err);
+    }
+    Ok(record_token_event(WRAPPED_TOKEN, Operation::Wrap, to, to, amount))
+}
+
+// Moves WICP between wallets. Used by `transfer_token`.
+pub(crate) fn transfer_wrapped(from: Principal, to: Principal, amount: u128) -> Result<u64, TransferError> {
+    if amount == 0 {
+        return Err(TransferError::InvalidAmount);
+    }
+    debit_wallet(from, WRAPPED_TOKEN, amount, 0)?;
+    if let Err(err) = credit_wallet(to, WRAPPED_TOKEN, amount) {
+        credit_wallet(from, WRAPPED_TOKEN, amount)?;
+        return Err(err);
+    }
+    Ok(record_token_event(WRAPPED_TOKEN, Operation::Transfer, from, to, amount))
+}
+
+// WICP metadata in the shape of the other tokens.
+pub(crate) fn wrapped_token() -> Token {
+    Token {
+        name: "Wrapped ICP".to_string(),
+        symbol: WRAPPED_TOKEN.to_string(),
+        decimals: 8,
+        total_supply: WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow()),
+        fee: 0,
+    }
+}
+
+// Any holder can unwrap its WICP. WICP is only minted against ICP received, so the reserves always cover it. The
+// WICP is burned before the ICP goes out, so it cannot be spent again while the ledger call is in flight, and is
+// restored if the ICP transfer fails.
+async fn unwrap_with(ledger: &impl IcpLedger, caller: Principal, amount: u128) -> Result<u64, TransferError> {
+    if amount <= ledger.fee() {
+        return Err(TransferError::InvalidAmount);
+    }
+    let wrapped_supply = WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow())
+        .checked_sub(amount)
+        .ok_or(TransferError::InsufficientReserves)?;
+    let pending_unwraps = PENDING_UNWRAPS.with(|pending| *pending.borrow())
+        .checked_add(amount)
+        .ok_or(TransferError::OverflowError)?;
+
+    debit_wallet(caller, WRAPPED_TOKEN, amount, 0)?;
+    WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow_mut() = wrapped_supply);
+    PENDING_UNWRAPS.with(|pending| *pending.borrow_mut() = pending_unwraps);
+
+    let result = ledger.transfer(caller, amount).await;
+    adjust(&PENDING_UNWRAPS, |pending| pending.checked_sub(amount))?;
+    match result {
+        Ok(icp_block_index) => {
+            record_token_event(WRAPPED_TOKEN, Operation::Unwrap, caller, caller, amount);
+            Ok(icp_block_index)
+        }
+        Err(err) => {
+            adjust(&WRAPPED_SUPPLY, |supply| supply.checked_add(amount))?;
+            credit_wallet(caller, WRAPPED_TOKEN, amount)?;
+            Err(err)
+        }
+    }
+}
+
+#[update]
+fn set_icp_ledger(ledger: Principal, fee: u128) -> Result<(), TransferError> {
+    if !is_owner() {
+        return Err(TransferError::Unauthorized);
+    }
+    WRAP_CONFIG.with(|config| *config.borrow_mut() = Some(WrapConfig { ledger, fee }));
+    Ok(())
+}
+
+// Takes `amount` ICP the caller approved for this canister (plus the ledger fee) and mints as much WICP.
+// Returns the WICP block index.
+#[update]
+async fn wrap(amount: u128) -> Result<u64, TransferError> {
+    wrap_with(&ledger()?, get_caller(), amount).await
+}
+
+// Burns `amount` WICP and sends as much ICP, minus the ledger fee, to the caller. Returns the ICP block index.
+#[update]
+async fn unwrap(amount: u128) -> Result<u64, TransferError> {
+    unwrap_with(&ledger()?, get_caller(), amount).await
+}
+
+// The WICP balance of `owner`, which is how much it can unwrap.
+#[query]
+fn get_wrapped_balance(owner: Principal) -> u128 {
+    token_balance_of(&owner, WRAPPED_TOKEN)
+}
+
+#[query]
+fn get_wrap_info() -> WrapInfo {
+    WrapInfo {
+        config: WRAP_CONFIG.with(|config| config.borrow().clone()),
+        wrapped_supply: WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow()),
+        pending_unwraps: PENDING_UNWRAPS.with(|pending| *pending.borrow()),
+    }
+}
+
+// `reserves` falls back to u128::MAX for balances that do not fit, so a sum that overflows is never balanced.
+fn is_balanced(reserves: u128, pending_sweeps: u128, wrapped_supply: u128, pending_unwraps: u128) -> bool {
+    match (reserves.checked_add(pending_sweeps), wrapped_supply.checked_add(pending_unwraps)) {
+        (Some(held), Some(owed)) => held == owed,
+        _ => false,
+    }
+}
+
+async fn check_reserves_with(ledger: &impl IcpLedger) -> Result<ReserveReport, TransferError> {
+    let reserves = ledger.reserves().await?;
+    let wrapped_supply = WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow());
+    let pending_unwraps = PENDING_UNWRAPS.with(|pending| *pending.borrow());
+    let pending_sweeps = deposits::pending_sweeps();
+    Ok(ReserveReport {
+        reserves,
+        wrapped_supply,
+        pending_unwraps,
+        pending_sweeps,
+        balanced: is_balanced(reserves, pending_sweeps, wrapped_supply, pending_unwraps),
+    })
+}
+
+#[update]
+async fn check_reserves() -> Result<ReserveReport, TransferError> {
+    check_reserves_with(&ledger()?).await
+}
+
+pub(crate) fn save() -> Result<Vec<u8>, candid::Error> {
+    candid::encode_one((
+        WRAP_CONFIG.with(|config| config.take()),
+        WRAPPED_SUPPLY.with(|supply| supply.take()),
+        PENDING_UNWRAPS.with(|pending| pending.take()),
+    ))
+}
+
+pub(crate) fn restore(bytes: &[u8]) -> Result<(), candid::Error> {
+    let (config, supply, pending) = candid::decode_one(bytes)?;
+    WRAP_CONFIG.with(|state| *state.borrow_mut() = config);
+    WRAPPED_SUPPLY.with(|state| *state.borrow_mut() = supply);
+    PENDING_UNWRAPS.with(|state| *state.borrow_mut() = pending);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn reset() {
+    WRAP_CONFIG.with(|config| *config.borrow_mut() = None);
+    WRAPPED_SUPPLY.with(|wrapped| *wrapped.borrow_mut() = 0);
+    PENDING_UNWRAPS.with(|pending| *pending.borrow_mut() = 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{balance_of, get_token_info, mint, reset_state, test_utils, OWNER};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use test_utils::block_on;
+
+    const FEE: u128 = 10;
+
+    // In-memory ICP ledger that answers every call immediately.
+    #[derive(Default)]
+    struct MockLedger {
+        balances: RefCell<HashMap<Principal, u128>>,
+        reserves: Cell<u128>,
+        reject: Cell<bool>,
+    }
+
+    impl IcpLedger for MockLedger {
+        fn fee(&self) -> u128 {
+            FEE
+        }
+
+        async fn transfer_from(&self, from: Principal, amount: u128) -> Result<u64, TransferError> {
+            let mut balances = self.balances.borrow_mut();
+            let balance = balances.entry(from).or_insert(0);
+            if self.reject.get() || *balance < amount + FEE {
+                return Err(TransferError::IcpTransferFailed("InsufficientFunds".to_string()));
+            }
+            *balance -= amount + FEE;
+            self.reserves.set(self.reserves.get() + amount);
+            Ok(0)
+        }
+
+        async fn transfer(&self, to: Principal, amount: u128) -> Result<u64, TransferError> {
+            if self.reject.get() {
+                return Err(TransferError::CanisterCallFailed("SysTransient: unavailable".to_string()));
+            }
+            self.reserves.set(self.reserves.get() - amount);
+            *self.balances.borrow_mut().entry(to).or_insert(0) += amount - FEE;
+            Ok(1)
+        }
+
+        async fn reserves(&self) -> Result<u128, TransferError> {
+            Ok(self.reserves.get())
+        }
+    }
+
+    #[test]
+    fn test_wrap_and_unwrap() {
+        reset_state();
+        let owner = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let user = Principal::from_text("aaaaa-aa").unwrap();
+        OWNER.with(|o| *o.borrow_mut() = owner);
+        let ledger = MockLedger::default();
+        ledger.balances.borrow_mut().insert(user, 1000);
+
+        assert!(matches!(block_on(wrap_with(&ledger, user, 991)), Err(TransferError::IcpTransferFailed(_))));
+        assert_eq!(get_wrapped_balance(user), 0);
+        assert_eq!(block_on(wrap_with(&ledger, user, 500)).unwrap(), 0);
+        assert_eq!((get_wrapped_balance(user), ledger.balances.borrow()[&user]), (500, 490));
+        assert_eq!(balance_of(&user), 0);
+
+        // Minted ICPT is a different token and cannot draw on the reserves; WICP can, whoever holds it.
+        test_utils::set_caller(owner);
+        let holder = Principal::anonymous();
+        let supply = get_token_info().total_supply;
+        assert!(mint(holder, 100).is_ok());
+        assert!(matches!(block_on(unwrap_with(&ledger, holder, 50)), Err(TransferError::InsufficientBalance)));
+        assert!(transfer_wrapped(user, holder, 100).is_ok());
+        assert!(matches!(block_on(unwrap_with(&ledger, user, 600)), Err(TransferError::InsufficientReserves)));
+        assert!(matches!(block_on(unwrap_with(&ledger, user, 450)), Err(TransferError::InsufficientBalance)));
+        assert!(matches!(block_on(unwrap_with(&ledger, user, FEE)), Err(TransferError::InvalidAmount)));
+
+        ledger.reject.set(true);
+        assert!(matches!(block_on(unwrap_with(&ledger, user, 200)), Err(TransferError::CanisterCallFailed(_))));
+        assert_eq!((get_wrapped_balance(user), get_wrap_info().wrapped_supply, get_wrap_info().pending_unwraps), (400, 500, 0));
+        ledger.reject.set(false);
+
+        assert_eq!(block_on(unwrap_with(&ledger, user, 200)).unwrap(), 1);
+        assert_eq!((get_wrapped_balance(user), ledger.balances.borrow()[&user]), (200, 680));
+        assert_eq!(block_on(unwrap_with(&ledger, holder, 50)).unwrap(), 1);
+        assert_eq!((get_wrapped_balance(holder), ledger.balances.borrow()[&holder]), (50, 40));
+        let report = block_on(check_reserves_with(&ledger)).unwrap();
+        assert_eq!((report.reserves, report.wrapped_supply, report.balanced), (250, 250, true));
+        assert_eq!(get_token_info().total_supply, supply + 100);
+        assert_eq!(wrapped_token().total_supply, 250);
+    }
+
+    #[test]
+    fn test_is_balanced() {
+        assert!(is_balanced(300, 20, 310, 10));
+        assert!(!is_balanced(300, 20, 300, 10));
+        // Reserves reported as u128::MAX plus pending sweeps overflow instead of panicking.
+        assert!(!is_balanced(u128::MAX, 1, u128::MAX, 1));
+        assert!(!is_balanced(u128::MAX, 0, u128::MAX, 1));
+    }
+}
@@ -0,0 +1,103 @@
+// Creates a ledger through the factory on a PocketIC replica, then upgrades both and checks that their state
+// survives. Needs the PocketIC server (`POCKET_IC_BIN`) and the gzipped release wasm, which `ICP_TOKEN_WASM`
+// points at:
+//
+//     cargo build --release --target wasm32-unknown-unknown
+//     gzip -k target/wasm32-unknown-unknown/release/icp_token_wallet.wasm
+//     ICP_TOKEN_WASM=target/wasm32-unknown-unknown/release/icp_token_wallet.wasm.gz cargo test -- --ignored
+
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use pocket_ic::{query_candid, update_candid_as, PocketIc};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct InitArgs {
+    name: String,
+    symbol: String,
+    decimals: u8,
+    initial_supply: u128,
+    owner: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct CreatedCanister {
+    canister_id: Principal,
+    init_args: InitArgs,
+    wasm_version: Option<u64>,
+}
+
+// Errors decode as `Reserved`; the test only needs to tell them apart from successes.
+type UpgradeResults = Vec<(Principal, Result<(), Reserved>)>;
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Balance {
+    total: u128,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Token {
+    name: String,
+    symbol: String,
+}
+
+fn init_args(name: &str, symbol: &str, owner: Principal) -> InitArgs {
+    InitArgs { name: name.to_string(), symbol: symbol.to_string(), decimals: 8, initial_supply: 1000, owner: Some(owner) }
+}
+
+fn token_wasm() -> Vec<u8> {
+    let path = std::env::var("ICP_TOKEN_WASM").expect("ICP_TOKEN_WASM must point at the gzipped canister wasm");
+    std::fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+}
+
+fn balance(pic: &PocketIc, canister_id: Principal, owner: Principal) -> u128 {
+    let (balance,): (Balance,) = query_candid(pic, canister_id, "get_balance", (owner,)).unwrap();
+    balance.total
+}
+
+fn created_canisters(pic: &PocketIc, factory: Principal) -> Vec<CreatedCanister> {
+    let (created,): (Vec<CreatedCanister>,) = query_candid(pic, factory, "get_created_canisters", ()).unwrap();
+    created
+}
+
+#[test]
+#[ignore = "needs the PocketIC server and the canister wasm"]
+fn test_create_and_upgrade_token_canister() {
+    let wasm = token_wasm();
+    let pic = PocketIc::new();
+    let owner = Principal::self_authenticating(b"owner");
+    let user = Principal::self_authenticating(b"user");
+
+    let factory = pic.create_canister();
+    pic.add_cycles(factory, 10_000_000_000_000);
+    let arg = candid::encode_one(Some(init_args("Factory", "FCT", owner))).unwrap();
+    pic.install_canister(factory, wasm.clone(), arg, None);
+
+    let (version,): (Result<u64, Reserved>,) =
+        update_candid_as(&pic, factory, owner, "set_ledger_wasm", (wasm.clone(),)).unwrap();
+    assert_eq!(version, Ok(1));
+    let (created,): (Result<Principal, Reserved>,) =
+        update_candid_as(&pic, factory, owner, "create_token_canister", (init_args("Gold", "GLD", owner),)).unwrap();
+    let ledger = created.unwrap();
+    let (minted,): (Result<bool, Reserved>,) = update_candid_as(&pic, ledger, owner, "mint", (user, 500u128)).unwrap();
+    assert_eq!(minted, Ok(true));
+    assert_eq!((balance(&pic, ledger, owner), balance(&pic, ledger, user)), (1000, 500));
+    assert_eq!(created_canisters(&pic, factory)[0].wasm_version, Some(1));
+
+    // Upgrading with a newer wasm keeps the created ledger's balances and metadata.
+    let (version,): (Result<u64, Reserved>,) =
+        update_candid_as(&pic, factory, owner, "set_ledger_wasm", (wasm.clone(),)).unwrap();
+    assert_eq!(version, Ok(2));
+    let (upgraded,): (Result<UpgradeResults, Reserved>,) =
+        update_candid_as(&pic, factory, owner, "upgrade_token_canisters", ()).unwrap();
+    assert_eq!(upgraded, Ok(vec![(ledger, Ok(()))]));
+    assert_eq!((balance(&pic, ledger, owner), balance(&pic, ledger, user)), (1000, 500));
+    let (token,): (Token,) = query_candid(&pic, ledger, "get_token_info", ()).unwrap();
+    assert_eq!((token.name.as_str(), token.symbol.as_str()), ("Gold", "GLD"));
+
+    // The factory keeps its own records across an upgrade as well.
+    pic.upgrade_canister(factory, wasm, candid::encode_args(()).unwrap(), None).unwrap();
+    let created = created_canisters(&pic, factory);
+    assert_eq!(created.len(), 1);
+    assert_eq!((created[0].canister_id, created[0].wasm_version), (ledger, Some(2)));
+    assert_eq!(created[0].init_args, init_args("Gold", "GLD", owner));
+    assert_eq!(balance(&pic, factory, owner), 1000);
+}